@@ -0,0 +1,80 @@
+//! Lexes the same generated source with the old char-based tokenizer,
+//! with `tokenize`, which now builds owned tokens from the `Lexer`, with
+//! the include `Loader` the assembler reads sources through, and with the
+//! `Lexer` alone, which borrows from the source.
+//!
+//! Run with `cargo bench --bench lexer`.
+
+extern crate mcpu;
+
+#[path = "lexer/old.rs"]
+mod old;
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use mcpu::assembler::tokenize;
+use mcpu::include::{Loader, VirtualFileSystem};
+use mcpu::lexer::Lexer;
+
+const LINES: usize = 100_000;
+const RUNS: u32 = 20;
+
+fn source() -> String {
+    let mut source = String::new();
+    for i in 0..LINES / 10 {
+        source.push_str(&format!("loop{}:\n", i));
+        source.push_str("    push 0x1F          ; counter\n");
+        source.push_str("    push value + 1\n");
+        source.push_str("    add\n");
+        source.push_str("    db name 1, 2, \"text\\n\"\n");
+        source.push('\n');
+        source.push_str("    push (1 << 3) | 4\n");
+        source.push_str("    jp neq\n");
+        source.push_str("    mov dst, src\n");
+        source.push_str(&format!("    jmp loop{}\n", i));
+    }
+    source
+}
+
+// Runs each case in turn, so a slow stretch of the machine hits them all
+// alike, and prints the best time of each.
+fn measure(len: usize, cases: &mut [(&str, &mut dyn FnMut() -> usize)]) {
+    let mut best = vec![(Duration::MAX, 0); cases.len()];
+    for _ in 0..RUNS {
+        for ((_, run), best) in cases.iter_mut().zip(&mut best) {
+            let start = Instant::now();
+            let count = black_box(run());
+            *best = (best.0.min(start.elapsed()), count);
+        }
+    }
+    for ((name, _), (best, count)) in cases.iter().zip(best) {
+        let throughput = len as f64 / best.as_secs_f64() / 1_000_000.0;
+        println!("{:<10} {:>10.2?} {:>8.1} MB/s  {} tokens", name, best, throughput, count);
+    }
+}
+
+fn main() {
+    let source = source();
+    println!("{} bytes, best of {} runs", source.len(), RUNS);
+
+    assert_eq!(old::tokenize(&source).unwrap().len(), tokenize(&source).unwrap().len());
+
+    let mut fs = VirtualFileSystem::new();
+    fs.add("main.s", &source);
+    let loader = Loader::new(fs);
+    measure(
+        source.len(),
+        &mut [
+            ("old", &mut || old::tokenize(black_box(&source)).unwrap().len()),
+            ("tokenize", &mut || tokenize(black_box(&source)).unwrap().len()),
+            ("loader", &mut || loader.load(black_box("main.s")).unwrap().tokens.len()),
+            ("lexer", &mut || {
+                Lexer::new(black_box(&source)).fold(0, |count, lexeme| {
+                    black_box(lexeme.unwrap());
+                    count + 1
+                })
+            }),
+        ],
+    );
+}
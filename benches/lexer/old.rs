@@ -0,0 +1,338 @@
+//! The char-based tokenizer `tokenize` used before `lexer::Lexer`, kept
+//! unchanged apart from its error type so the bench can compare the two.
+
+use mcpu::assembler::Token;
+
+// only ever shown through `Debug` when unwrapping
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct TokenizerError {
+    pub pattern: String,
+    pub position: usize,
+}
+
+pub fn tokenize(source: &str) -> Result<Vec<Token>, TokenizerError> {
+    let mut expect_letter = true;
+    let mut expect_newline = true;
+    let mut expect_number = false;
+    let mut expect_space = true;
+    let mut expect_hex = true;
+
+    let mut tokens: Vec<Token> = Vec::new();
+
+    let mut curr_token = String::new();
+
+    let chars: Vec<char> = source.chars().collect();
+
+    let mut i = 0usize;
+
+    while i < chars.len() {
+        let el = chars[i];
+
+        let res = match el {
+            // any run of spaces and tabs separates tokens; `\r` comes from
+            // CRLF line endings
+            ' ' | '\t' | '\r' => {
+                // after a comma or an operator the operand is still expected
+                if expect_space || !curr_token.is_empty() || expect_number {
+                    push_pending(&mut tokens, &mut curr_token, i, expect_letter, expect_number)?;
+
+                    expect_letter = true;
+                    expect_newline = true;
+                    expect_number = false;
+                    expect_space = true;
+                    expect_hex = true;
+                }
+
+                true
+            }
+            '\n' if expect_newline => {
+                push_pending(&mut tokens, &mut curr_token, i, expect_letter, expect_number)?;
+
+                expect_letter = true;
+                expect_newline = true;
+                expect_number = false;
+                expect_space = true;
+                expect_hex = true;
+
+                // blank lines have no tokens of their own
+                if !matches!(tokens.last(), None | Some(Token::EOL(_))) {
+                    tokens.push(Token::EOL(i));
+                }
+
+                true
+            }
+            ',' if expect_space => {
+                push_pending(&mut tokens, &mut curr_token, i, expect_letter, expect_number)?;
+                tokens.push(Token::Comma(i));
+
+                expect_letter = true;
+                expect_newline = false;
+                expect_number = false;
+                expect_space = false;
+                expect_hex = true;
+
+                true
+            }
+            ';' => {
+                push_pending(&mut tokens, &mut curr_token, i, expect_letter, expect_number)?;
+
+                let end = chars[i..].iter().position(|c| *c == '\n').map_or(chars.len(), |len| i + len);
+                let text: String = chars[i + 1..end].iter().collect();
+                tokens.push(Token::Comment(i, text.trim_end().to_string()));
+                i = end - 1;
+
+                // only the end of the line can follow
+                expect_letter = false;
+                expect_newline = true;
+                expect_number = false;
+                expect_space = false;
+                expect_hex = false;
+
+                true
+            }
+            '"' | '\'' if expect_letter && curr_token.is_empty() => {
+                let (bytes, end) = string_literal(&chars, i)?;
+
+                if el == '"' {
+                    tokens.push(Token::Str(i, bytes));
+                } else if bytes.len() == 1 {
+                    tokens.push(Token::Number(i, bytes[0] as u16));
+                } else {
+                    return Err(TokenizerError {
+                        position: i,
+                        pattern: chars[i..=end].iter().collect(),
+                    });
+                }
+                i = end;
+
+                expect_letter = false;
+                expect_newline = true;
+                expect_number = false;
+                expect_space = true;
+                expect_hex = false;
+
+                true
+            }
+            '+' | '-' | '*' | '/' | '%' | '&' | '|' | '^' | '~' | '(' | ')' | '<' | '>' | '=' | '!'
+                if expect_space || (curr_token.is_empty() && !expect_number) =>
+            {
+                if !curr_token.is_empty() {
+                    push_pending(&mut tokens, &mut curr_token, i, expect_letter, expect_number)?;
+                }
+
+                let start = i;
+                let next = chars.get(i + 1).cloned();
+                if el == '<' || el == '>' {
+                    if next == Some(el) || next == Some('=') {
+                        i += 1;
+                    }
+                } else if el == '=' || el == '!' {
+                    if next == Some('=') {
+                        i += 1;
+                    } else {
+                        return Err(TokenizerError {
+                            position: i,
+                            pattern: el.to_string(),
+                        });
+                    }
+                }
+                tokens.push(Token::Operator(start, chars[start..=i].iter().collect()));
+
+                if el == ')' {
+                    expect_letter = false;
+                    expect_newline = true;
+                    expect_space = true;
+                    expect_hex = false;
+                } else {
+                    expect_letter = true;
+                    expect_newline = false;
+                    expect_space = false;
+                    expect_hex = true;
+                }
+                expect_number = false;
+
+                true
+            }
+            ':' if expect_letter && !curr_token.is_empty() => {
+                push_pending(&mut tokens, &mut curr_token, i, expect_letter, expect_number)?;
+                tokens.push(Token::Colon(i));
+
+                expect_letter = false;
+                expect_newline = true;
+                expect_number = false;
+                expect_space = true;
+                expect_hex = false;
+
+                true
+            }
+            '.' if expect_letter => {
+                curr_token.push(el);
+                expect_hex = false;
+                expect_newline = false;
+                expect_space = false;
+                true
+            }
+            '0'..='9' | 'A'..='Z' | 'a'..='z' => {
+                if expect_hex && el == '0' && chars.get(i + 1) == Some(&'x') {
+                    expect_number = true;
+                    expect_letter = false;
+                    expect_newline = false;
+                    expect_space = false;
+                    expect_hex = false;
+                    i += 1;
+                    true
+                } else if expect_number {
+                    curr_token.push(el);
+                    expect_newline = true;
+                    expect_space = true;
+                    true
+                } else if expect_letter {
+                    curr_token.push(el);
+                    expect_number = false;
+                    expect_hex = false;
+                    expect_newline = true;
+                    expect_space = true;
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => false,
+        };
+
+        if !res {
+            if el == '\n' {
+                return Err(TokenizerError {
+                    position: i,
+                    pattern: "\\n".to_string(),
+                });
+            } else {
+                return Err(TokenizerError {
+                    position: i,
+                    pattern: el.to_string(),
+                });
+            }
+        }
+
+        i += 1;
+    }
+
+    Ok(tokens)
+}
+
+// Reads the quoted literal starting at `start`, returning its bytes and the
+// index of the closing quote.
+fn string_literal(chars: &[char], start: usize) -> Result<(Vec<u8>, usize), TokenizerError> {
+    let quote = chars[start];
+    let mut bytes = Vec::new();
+    let mut i = start + 1;
+
+    loop {
+        let el = match chars.get(i) {
+            Some(el) => *el,
+            None => {
+                return Err(TokenizerError {
+                    position: i,
+                    pattern: "end of input".to_string(),
+                })
+            }
+        };
+
+        match el {
+            '\n' => {
+                return Err(TokenizerError {
+                    position: i,
+                    pattern: "\\n".to_string(),
+                })
+            }
+            '\\' => {
+                let escaped = chars.get(i + 1).cloned().unwrap_or(' ');
+                let byte = match escaped {
+                    'n' => b'\n',
+                    'r' => b'\r',
+                    't' => b'\t',
+                    '0' => 0,
+                    '\\' => b'\\',
+                    '"' => b'"',
+                    '\'' => b'\'',
+                    'x' => {
+                        let digits: String = chars.iter().skip(i + 2).take(2).collect();
+                        match u8::from_str_radix(&digits, 16) {
+                            Ok(byte) if digits.len() == 2 => {
+                                i += 2;
+                                byte
+                            }
+                            _ => {
+                                return Err(TokenizerError {
+                                    position: i,
+                                    pattern: format!("\\x{}", digits),
+                                })
+                            }
+                        }
+                    }
+                    _ => {
+                        return Err(TokenizerError {
+                            position: i,
+                            pattern: format!("\\{}", escaped),
+                        })
+                    }
+                };
+                bytes.push(byte);
+                i += 2;
+            }
+            el if el == quote => return Ok((bytes, i)),
+            el => {
+                let mut buf = [0; 4];
+                bytes.extend_from_slice(el.encode_utf8(&mut buf).as_bytes());
+                i += 1;
+            }
+        }
+    }
+}
+
+fn push_pending(
+    tokens: &mut Vec<Token>,
+    curr_token: &mut String,
+    i: usize,
+    expect_letter: bool,
+    expect_number: bool,
+) -> Result<(), TokenizerError> {
+    if expect_letter {
+        if curr_token.is_empty() {
+            return Ok(());
+        }
+        let position = i - curr_token.len();
+        let is_decimal = !curr_token.is_empty() && curr_token.chars().all(|c| c.is_ascii_digit());
+
+        if is_decimal {
+            match curr_token.parse::<u16>() {
+                Ok(number) => tokens.push(Token::Number(position, number)),
+                Err(_) => {
+                    return Err(TokenizerError {
+                        position,
+                        pattern: curr_token.clone(),
+                    })
+                }
+            }
+        } else {
+            tokens.push(Token::Word(position, curr_token.clone()));
+        }
+        curr_token.clear();
+    } else if expect_number {
+        let position = i - curr_token.len() - 2;
+        match u16::from_str_radix(curr_token, 16) {
+            Ok(number) => tokens.push(Token::Number(position, number)),
+            Err(_) => {
+                return Err(TokenizerError {
+                    position,
+                    pattern: format!("0x{}", curr_token),
+                })
+            }
+        }
+        curr_token.clear();
+    }
+
+    Ok(())
+}
+
@@ -0,0 +1,23 @@
+push 0x05
+jp
+dw sum 0x0
+dw i 0xa
+push i
+load
+push sum
+load
+add
+push sum
+store
+push 0x1
+push i
+load
+sub
+push i
+store
+push i
+load
+push 0x05
+jp neq
+push sum
+load
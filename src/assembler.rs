@@ -1,17 +1,63 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 
-#[derive(Debug, Clone)]
+use ast::{self, DirectiveKind, Instruction, ItemKind, Operand, Program};
+use expr::{BinaryOp, Expr, Value};
+use lexer::Lexer;
+use listing::{Listing, ListingLine, Symbol};
+use object::{Export, Object, Relocation, Section, Target};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Token {
     Word(usize, String),
-    Number(usize, u8),
+    Number(usize, u16),
+    Str(usize, Vec<u8>),
+    Operator(usize, String),
+    Colon(usize),
+    Comma(usize),
+    /// Text after a `;` up to the end of the line.
+    Comment(usize, String),
     EOL(usize),
 }
 
+impl Token {
+    pub fn position(&self) -> usize {
+        match self {
+            Token::Word(position, _)
+            | Token::Number(position, _)
+            | Token::Str(position, _)
+            | Token::Operator(position, _)
+            | Token::Comment(position, _) => *position,
+            Token::Colon(position) | Token::Comma(position) | Token::EOL(position) => *position,
+        }
+    }
+
+    /// Moves the token `offset` positions further, e.g. into the range of
+    /// its file in a `SourceMap`.
+    pub fn shifted(self, offset: usize) -> Token {
+        match self {
+            Token::Word(position, word) => Token::Word(position + offset, word),
+            Token::Number(position, number) => Token::Number(position + offset, number),
+            Token::Str(position, bytes) => Token::Str(position + offset, bytes),
+            Token::Operator(position, op) => Token::Operator(position + offset, op),
+            Token::Colon(position) => Token::Colon(position + offset),
+            Token::Comma(position) => Token::Comma(position + offset),
+            Token::Comment(position, text) => Token::Comment(position + offset, text),
+            Token::EOL(position) => Token::EOL(position + offset),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct TokenizerError {
-    pattern: String,
-    position: usize,
+    pub(crate) pattern: String,
+    pub(crate) position: usize,
+}
+
+impl TokenizerError {
+    pub fn position(&self) -> usize {
+        self.position
+    }
 }
 
 impl fmt::Display for TokenizerError {
@@ -24,473 +70,928 @@ impl fmt::Display for TokenizerError {
     }
 }
 
+/// Splits `source` into owned tokens. Positions are byte offsets; see
+/// `lexer::Lexer` for borrowing the text instead.
 pub fn tokenize(source: &str) -> Result<Vec<Token>, TokenizerError> {
-    let mut expect_letter = true;
-    let mut expect_newline = true;
-    let mut expect_number = false;
-    let mut expect_space = true;
-    let mut expect_hex = true;
-
-    let mut tokens: Vec<Token> = Vec::new();
-
-    let mut curr_token = String::new();
-
-    let chars: Vec<char> = source.chars().collect();
-
-    let mut i = 0usize;
-
-    while i < chars.len() {
-        let el = chars[i];
-
-        let res = match el {
-            ' ' => if expect_space {
-                if expect_letter {
-                    tokens.push(Token::Word(i - curr_token.len(), curr_token.clone()));
-                    curr_token = String::new();
-                } else if expect_number {
-                    tokens.push(Token::Number(
-                        i - curr_token.len() - 2,
-                        u8::from_str_radix(&curr_token, 16).unwrap(),
-                    ));
-                    curr_token = String::new();
-                }
+    // sources rarely have more than one token per three bytes, so this seldom grows
+    let mut tokens = Vec::with_capacity(source.len() / 3);
+    for lexeme in Lexer::new(source) {
+        tokens.push(lexeme?.to_token());
+    }
+    Ok(tokens)
+}
 
-                expect_letter = true;
-                expect_newline = true;
-                expect_number = false;
-                expect_space = true;
-                expect_hex = true;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParserErrorKind {
+    /// Another token was needed, e.g. an `EOL` or `,`. Its position is
+    /// meaningless.
+    Expected(Token),
+    /// A name was needed, e.g. a "section name".
+    ExpectedName(&'static str),
+    UnknownInstruction,
+    MissingOperand,
+    UnknownCondition,
+    /// `if`, `else` and the like after a label.
+    ConditionalAfterLabel,
+    /// `db FOO BAR`, which could name the data `FOO` or be two values.
+    AmbiguousName,
+    /// A `.pstr` string longer than its length byte can hold.
+    StringTooLong,
+    UnknownSymbol,
+    /// An imported symbol used where its value must be known.
+    NotAbsolute,
+    /// Overflow or division by zero in an expression.
+    Arithmetic,
+    ImportOutsideObject,
+    ElseWithoutIf,
+    EndifWithoutIf,
+    MissingEndif,
+    /// An `org` below the address already reached.
+    OrgBackwards { address: usize, target: usize },
+    ZeroAlignment,
+    OutOfMemory,
+    ByteOutOfRange(i64),
+    SizeOutOfRange(i64),
+}
 
-                true
-            } else {
-                false
-            },
-            '\n' => if expect_newline {
-                if expect_letter {
-                    tokens.push(Token::Word(i - curr_token.len(), curr_token.clone()));
-                    curr_token = String::new();
-                } else if expect_number {
-                    tokens.push(Token::Number(
-                        i - curr_token.len() - 2,
-                        u8::from_str_radix(&curr_token, 16).unwrap(),
-                    ));
-                    curr_token = String::new();
-                }
+impl fmt::Display for ParserErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParserErrorKind::Expected(token) => write!(f, "expected token {:?}", token),
+            ParserErrorKind::ExpectedName(name) => write!(f, "expected {}", name),
+            ParserErrorKind::UnknownInstruction => write!(f, "unknown instruction or directive"),
+            ParserErrorKind::MissingOperand => write!(f, "missing operand"),
+            ParserErrorKind::UnknownCondition => write!(f, "expected EOL or gt, lt, geq, leq, eq, neq"),
+            ParserErrorKind::ConditionalAfterLabel => write!(f, "conditionals must be on a line of their own"),
+            ParserErrorKind::AmbiguousName => write!(
+                f,
+                "ambiguous name; use a label or separate values with commas"
+            ),
+            ParserErrorKind::StringTooLong => write!(f, "string longer than 255 bytes"),
+            ParserErrorKind::UnknownSymbol => write!(f, "unknown label"),
+            ParserErrorKind::NotAbsolute => write!(f, "value is not known before linking"),
+            ParserErrorKind::Arithmetic => write!(f, "overflow or division by zero"),
+            ParserErrorKind::ImportOutsideObject => write!(f, "import is only allowed in object files"),
+            ParserErrorKind::ElseWithoutIf => write!(f, "else outside of an if block"),
+            ParserErrorKind::EndifWithoutIf => write!(f, "endif without an if"),
+            ParserErrorKind::MissingEndif => write!(f, "if without an endif"),
+            ParserErrorKind::OrgBackwards { address, target } => write!(
+                f,
+                "org {:02X} is below the current address {:02X}",
+                target, address
+            ),
+            ParserErrorKind::ZeroAlignment => write!(f, "alignment must not be zero"),
+            ParserErrorKind::OutOfMemory => write!(f, "data does not fit in memory"),
+            ParserErrorKind::ByteOutOfRange(value) => {
+                write!(f, "value {} is not between -128 and 255", value)
+            }
+            ParserErrorKind::SizeOutOfRange(value) => write!(f, "value {} is not between 0 and 256", value),
+        }
+    }
+}
 
-                expect_letter = true;
-                expect_newline = true;
-                expect_number = false;
-                expect_space = true;
-                expect_hex = true;
+#[derive(Debug, Clone)]
+pub struct ParserError<'a> {
+    pub(crate) token: &'a Token,
+    pub(crate) kind: ParserErrorKind,
+}
 
-                tokens.push(Token::EOL(i));
+impl<'a> ParserError<'a> {
+    pub fn token(&self) -> &'a Token {
+        self.token
+    }
 
-                true
-            } else {
-                false
-            },
-            '0'...'9' | 'A'...'Z' | 'a'...'z' => {
-                if expect_hex && el == '0' && chars[i + 1] == 'x' {
-                    expect_number = true;
-                    expect_letter = false;
-                    expect_newline = false;
-                    expect_space = false;
-                    expect_hex = false;
-                    i += 1;
-                    true
-                } else if expect_number {
-                    curr_token.push(el);
-                    expect_newline = true;
-                    expect_space = true;
-                    true
-                } else if expect_letter {
-                    curr_token.push(el);
-                    expect_number = false;
-                    expect_hex = false;
-                    expect_newline = true;
-                    expect_space = true;
-                    true
-                } else {
-                    false
-                }
+    pub fn kind(&self) -> &ParserErrorKind {
+        &self.kind
+    }
+}
+
+impl<'a> fmt::Display for ParserError<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}, found token {:?}", self.kind, self.token)
+    }
+}
+
+const MEMORY_SIZE: usize = 256;
+
+enum Data<'a> {
+    Raw(u8),
+    Byte(Expr<'a>),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Data<'a> {
+    fn len(&self) -> usize {
+        match self {
+            Data::Bytes(bytes) => bytes.len(),
+            _ => 1,
+        }
+    }
+}
+
+enum Statement<'a> {
+    Emit(Vec<Data<'a>>),
+    Reserve(Expr<'a>),
+    Org(Expr<'a>),
+    Align(Expr<'a>),
+    Equ(&'a str, Expr<'a>),
+    Section(&'a str),
+    Export(Vec<&'a Token>),
+    Import(Vec<&'a str>),
+}
+
+struct Line<'a> {
+    token: &'a Token,
+    labels: Vec<&'a Token>,
+    name: Option<&'a str>,
+    statement: Option<Statement<'a>>,
+    scope: String,
+    section: usize,
+    code: bool,
+    pseudo: bool,
+}
+
+/// Symbol table with support for local labels. `.name` labels belong to
+/// the closest global label above them, and numeric labels `1:` may be
+/// defined many times and are referred to as `1b` (backwards) or `1f`
+/// (forwards).
+///
+/// When assembling an object, labels are offsets into their section and
+/// imported symbols have no value until linking.
+#[derive(Default)]
+struct Symbols {
+    names: HashMap<String, i64>,
+    constants: HashSet<String>,
+    numeric: HashMap<u16, Vec<(usize, i64, Option<usize>)>>,
+    bases: HashMap<String, usize>,
+    imports: Vec<String>,
+    sections: Vec<Section>,
+}
+
+impl Symbols {
+    fn define(&mut self, name: &str, scope: &str, value: i64) {
+        self.names.insert(qualify(name, scope), value);
+    }
+
+    fn define_constant(&mut self, name: &str, scope: &str, value: i64) {
+        self.constants.insert(qualify(name, scope));
+        self.define(name, scope, value);
+    }
+
+    fn define_address(&mut self, name: &str, scope: &str, section: Option<usize>, address: i64) {
+        if let Some(section) = section {
+            self.bases.insert(qualify(name, scope), section);
+        }
+        self.define(name, scope, address);
+    }
+
+    fn define_label(&mut self, label: &Token, scope: &str, line: usize, section: Option<usize>, address: i64) {
+        match label {
+            Token::Word(_, name) => self.define_address(name, scope, section, address),
+            Token::Number(_, number) => {
+                self.numeric.entry(*number).or_default().push((line, address, section))
             }
-            _ => false,
+            _ => {}
+        }
+    }
+
+    // The value of a symbol and the section it is relative to, if any.
+    fn lookup(&self, name: &str, scope: &str, line: usize) -> Option<(i64, Option<usize>)> {
+        let qualified = qualify(name, scope);
+        if let Some(value) = self.names.get(&qualified) {
+            return Some((*value, self.bases.get(&qualified).cloned()));
+        }
+
+        let (number, direction) = name.split_at(name.len().saturating_sub(1));
+        let definitions = self.numeric.get(&number.parse::<u16>().ok()?)?;
+        let definition = match direction {
+            "b" => definitions.iter().rev().find(|def| def.0 <= line),
+            "f" => definitions.iter().find(|def| def.0 > line),
+            _ => None,
         };
+        definition.map(|def| (def.1, def.2))
+    }
 
-        if !res {
-            if el == '\n' {
-                return Err(TokenizerError {
-                    position: i,
-                    pattern: "\\n".to_string(),
-                });
-            } else {
-                return Err(TokenizerError {
-                    position: i,
-                    pattern: el.to_string(),
-                });
-            }
+    fn resolve(&self, name: &str, scope: &str, line: usize) -> Option<i64> {
+        self.lookup(name, scope, line).map(|(value, _)| value)
+    }
+
+    fn value(&self, name: &str, scope: &str, line: usize) -> Option<Value> {
+        if self.imports.iter().any(|import| import == name) {
+            return Some(Value {
+                base: Some(Target::Symbol(name.to_string())),
+                offset: 0,
+            });
         }
+        self.lookup(name, scope, line).map(|(offset, section)| Value {
+            base: section.map(Target::Section),
+            offset,
+        })
+    }
+}
 
-        i += 1;
+pub(crate) fn qualify(name: &str, scope: &str) -> String {
+    if name.starts_with('.') {
+        format!("{}{}", scope, name)
+    } else {
+        name.to_string()
     }
+}
 
-    Ok(tokens)
+// Global labels open a new scope for local labels. Labels generated for
+// macro expansions do not, so using a macro keeps the surrounding scope.
+pub(crate) fn opens_scope(label: &Token) -> Option<&str> {
+    match label {
+        Token::Word(_, name) if !name.starts_with('.') && !name.contains('@') => Some(name),
+        _ => None,
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct ParserError<'a> {
-    token: &'a Token,
-    expected: Token,
+pub(crate) fn opcode(mnemonic: &str) -> Option<u8> {
+    match mnemonic {
+        "halt" => Some(0x00),
+        "load" => Some(0x01),
+        "store" => Some(0x02),
+        "pop" => Some(0x04),
+        "add" => Some(0x05),
+        "sub" => Some(0x06),
+        "and" => Some(0x07),
+        "or" => Some(0x08),
+        "xor" => Some(0x09),
+        _ => None,
+    }
 }
 
-impl<'a> fmt::Display for ParserError<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "unexpected token {:?}, expected token {:?}",
-            self.token, self.expected
-        )
+pub(crate) fn condition(name: &str) -> Option<u8> {
+    match name {
+        "gt" => Some(0x01),
+        "lt" => Some(0x02),
+        "geq" => Some(0x03),
+        "leq" => Some(0x04),
+        "eq" => Some(0x05),
+        "neq" => Some(0x06),
+        _ => None,
     }
 }
 
-pub fn parse(tokens: &Vec<Token>) -> Result<Vec<u8>, ParserError> {
-    let mut i = 0;
-    let mut mem: Vec<u8> = Vec::new();
-    let mut name_table: HashMap<String, u8> = HashMap::new();
-    let mut instruction = 0;
+/// Assembles `tokens` into a memory image starting at address 0.
+///
+/// Symbols are collected in a first pass so operands may refer to labels
+/// defined further down; `org`, `ds`, `align` and `equ` operands may only
+/// use symbols defined before them. `section` directives have no effect
+/// here; lines are placed in the order they are written.
+pub fn parse(tokens: &[Token]) -> Result<Vec<u8>, ParserError<'_>> {
+    Assembler::new().assemble(tokens)
+}
 
-    while i < tokens.len() {
-        let token = &tokens[i];
+/// Assembler settings, for defining symbols from outside the source.
+#[derive(Debug, Clone, Default)]
+pub struct Assembler {
+    defines: Vec<(String, i64)>,
+}
 
-        match token {
-            Token::Word(_, word) => match word.to_lowercase().as_ref() {
-                "dw" => match &tokens[i + 1] {
-                    Token::Word(_, name) => match &tokens[i + 2] {
-                        Token::Number(_, number) => match tokens[i + 3] {
-                            Token::EOL(_) => {
-                                i += 4;
-                                name_table.insert(name.to_string(), instruction);
-                                instruction += 1;
-                                mem.push(*number);
-                            }
-                            _ => {
-                                return Err(ParserError {
-                                    token: &tokens[i + 2],
-                                    expected: Token::EOL(0),
-                                })
-                            }
-                        },
-                        _ => {
-                            return Err(ParserError {
-                                token: &tokens[i + 1],
-                                expected: Token::Number(0, 0),
-                            })
-                        }
-                    },
-                    Token::Number(_, number) => match tokens[i + 2] {
-                        Token::EOL(_) => {
-                            i += 3;
-                            instruction += 1;
-                            mem.push(*number);
-                        }
-                        _ => {
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler::default()
+    }
+
+    /// Defines a constant before the first line, as `equ name value` would.
+    pub fn define<S: Into<String>>(mut self, name: S, value: i64) -> Assembler {
+        self.defines.push((name.into(), value));
+        self
+    }
+
+    pub fn assemble<'a>(&self, tokens: &'a [Token]) -> Result<Vec<u8>, ParserError<'a>> {
+        self.assemble_with_listing(tokens).map(|(mem, _)| mem)
+    }
+
+    /// Assembles `tokens` and reports the address and bytes of every line.
+    pub fn assemble_with_listing<'a>(
+        &self,
+        tokens: &'a [Token],
+    ) -> Result<(Vec<u8>, Listing), ParserError<'a>> {
+        self.encode_with_listing(&ast::parse(tokens)?)
+    }
+
+    /// Encodes an already parsed program.
+    pub fn encode<'a>(&self, program: &Program<'a>) -> Result<Vec<u8>, ParserError<'a>> {
+        self.encode_with_listing(program).map(|(mem, _)| mem)
+    }
+
+    pub fn encode_with_listing<'a>(
+        &self,
+        program: &Program<'a>,
+    ) -> Result<(Vec<u8>, Listing), ParserError<'a>> {
+        let (lines, symbols) = define_symbols(program, &self.defines, false)?;
+        let (mut sections, listed) = encode(&lines, &symbols)?;
+        let mem = sections.swap_remove(0).data;
+
+        let mut names: Vec<Symbol> = symbols
+            .names
+            .iter()
+            .map(|(name, value)| Symbol {
+                name: name.clone(),
+                value: *value,
+                label: !symbols.constants.contains(name),
+            })
+            .collect();
+        names.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok((
+            mem,
+            Listing {
+                lines: listed,
+                symbols: names,
+            },
+        ))
+    }
+
+    /// Assembles `tokens` into a relocatable object. Every `section` starts
+    /// at offset 0, with code before the first `section` directive going
+    /// to `text`. Labels listed by `export` can be imported by other
+    /// objects with `import`.
+    pub fn assemble_object<'a>(&self, tokens: &'a [Token]) -> Result<Object, ParserError<'a>> {
+        self.encode_object(&ast::parse(tokens)?)
+    }
+
+    pub fn encode_object<'a>(&self, program: &Program<'a>) -> Result<Object, ParserError<'a>> {
+        let (lines, symbols) = define_symbols(program, &self.defines, true)?;
+        let (sections, _) = encode(&lines, &symbols)?;
+
+        let mut exports = Vec::new();
+        for (index, line) in lines.iter().enumerate() {
+            if let Some(Statement::Export(names)) = &line.statement {
+                for token in names {
+                    let name = match token {
+                        Token::Word(_, name) => name,
+                        _ => continue,
+                    };
+                    match symbols.lookup(name, &line.scope, index) {
+                        Some((value, section)) => exports.push(Export {
+                            name: qualify(name, &line.scope),
+                            section,
+                            value,
+                        }),
+                        None => {
                             return Err(ParserError {
-                                token: &tokens[i + 2],
-                                expected: Token::EOL(0),
+                                token,
+                                kind: ParserErrorKind::UnknownSymbol,
                             })
                         }
-                    },
-                    _ => {
-                        return Err(ParserError {
-                            token: &tokens[i + 1],
-                            expected: Token::Number(0, 0),
-                        })
-                    }
-                },
-                "halt" => match tokens[i + 1] {
-                    Token::EOL(_) => {
-                        i += 2;
-                        instruction += 1;
-                        mem.push(0x00);
                     }
-                    _ => {
-                        return Err(ParserError {
-                            token: &tokens[i + 1],
-                            expected: Token::EOL(0),
-                        })
-                    }
-                },
-                "load" => match tokens[i + 1] {
-                    Token::EOL(_) => {
-                        i += 2;
-                        instruction += 1;
-                        mem.push(0x01);
+                }
+            }
+        }
+
+        Ok(Object {
+            name: String::new(),
+            sections,
+            exports,
+            imports: symbols.imports,
+        })
+    }
+}
+
+// One open `if`/`ifdef`/`ifndef` block.
+struct Conditional<'a> {
+    token: &'a Token,
+    active: bool,
+    taken: bool,
+    in_else: bool,
+}
+
+// Turns a parsed statement into what `line` emits.
+fn lower<'a>(kind: &ItemKind<'a>, line: &mut Line<'a>) -> Result<(), ParserError<'a>> {
+    let directive = match kind {
+        ItemKind::Instruction(instruction) => {
+            line.statement = Some(Statement::Emit(instruction_data(instruction)?));
+            line.code = true;
+            line.pseudo = pseudo(&instruction.mnemonic);
+            return Ok(());
+        }
+        ItemKind::Directive(directive) => directive,
+        _ => return Ok(()),
+    };
+
+    let (name, statement) = match &directive.kind {
+        DirectiveKind::Word { name, value } => (*name, Statement::Emit(vec![Data::Byte(value.clone())])),
+        DirectiveKind::Bytes { name, values } => {
+            let data = values.iter().map(|value| match value {
+                ast::Data::Expr(expr) => Data::Byte(expr.clone()),
+                ast::Data::Str(bytes) => Data::Bytes(bytes),
+            });
+            (*name, Statement::Emit(data.collect()))
+        }
+        DirectiveKind::Asciz { name, text } => (*name, Statement::Emit(vec![Data::Bytes(text), Data::Raw(0)])),
+        DirectiveKind::Pstr { name, text } => (
+            *name,
+            Statement::Emit(vec![Data::Raw(text.len() as u8), Data::Bytes(text)]),
+        ),
+        DirectiveKind::Reserve { name, size } => (*name, Statement::Reserve(size.clone())),
+        DirectiveKind::Org(expr) => (None, Statement::Org(expr.clone())),
+        DirectiveKind::Align(expr) => (None, Statement::Align(expr.clone())),
+        DirectiveKind::Equ(name, expr) => (None, Statement::Equ(name, expr.clone())),
+        DirectiveKind::Section(name) => (None, Statement::Section(name)),
+        DirectiveKind::Export(names) => (None, Statement::Export(names.clone())),
+        DirectiveKind::Import(names) => (None, Statement::Import(names.clone())),
+        // handled while collecting symbols
+        _ => return Ok(()),
+    };
+    line.name = name;
+    line.statement = Some(statement);
+    Ok(())
+}
+
+fn instruction_data<'a>(instruction: &Instruction<'a>) -> Result<Vec<Data<'a>>, ParserError<'a>> {
+    let operand = |index: usize| match instruction.operands.get(index) {
+        Some(Operand::Expr(expr)) => Ok(expr.clone()),
+        _ => Err(ParserError {
+            token: instruction.token,
+            kind: ParserErrorKind::MissingOperand,
+        }),
+    };
+
+    let mnemonic = instruction.mnemonic.as_ref();
+    let data = match mnemonic {
+        "jmp" | "jz" => {
+            let condition = if mnemonic == "jz" { 0x05 } else { 0x00 };
+            let mut data = push(jump_target(operand(0)?));
+            data.extend(vec![Data::Raw(0x0A), Data::Raw(condition)]);
+            data
+        }
+        "inc" | "dec" => {
+            let var = operand(0)?;
+            let one = || push(Expr::Number(var.token(), 1));
+            let mut data = Vec::new();
+            if mnemonic == "inc" {
+                data.extend(push(var.clone()));
+                data.push(Data::Raw(0x01));
+                data.extend(one());
+                data.push(Data::Raw(0x05));
+            } else {
+                // sub computes the top of the stack minus the value below it
+                data.extend(one());
+                data.extend(push(var.clone()));
+                data.push(Data::Raw(0x01));
+                data.push(Data::Raw(0x06));
+            }
+            data.extend(push(var));
+            data.push(Data::Raw(0x02));
+            data
+        }
+        "mov" => {
+            let mut data = push(operand(1)?);
+            data.push(Data::Raw(0x01));
+            data.extend(push(operand(0)?));
+            data.push(Data::Raw(0x02));
+            data
+        }
+        "not" => vec![Data::Raw(0x03), Data::Raw(0xFF), Data::Raw(0x09)],
+        "push" => push(operand(0)?),
+        "jp" => {
+            let condition = match instruction.operands.first() {
+                Some(Operand::Condition(_, condition)) => *condition,
+                _ => 0x00,
+            };
+            vec![Data::Raw(0x0A), Data::Raw(condition)]
+        }
+        mnemonic => match opcode(mnemonic) {
+            Some(opcode) => vec![Data::Raw(opcode)],
+            None => {
+                return Err(ParserError {
+                    token: instruction.token,
+                    kind: ParserErrorKind::UnknownInstruction,
+                })
+            }
+        },
+    };
+    Ok(data)
+}
+
+fn pseudo(mnemonic: &str) -> bool {
+    matches!(mnemonic, "jmp" | "jz" | "inc" | "dec" | "mov" | "not")
+}
+
+fn push(expr: Expr<'_>) -> Vec<Data<'_>> {
+    vec![Data::Raw(0x03), Data::Byte(expr)]
+}
+
+// `jp` continues after the address it jumped to, so jumping to a label means
+// jumping to the byte before it.
+fn jump_target(target: Expr<'_>) -> Expr<'_> {
+    let token = target.token();
+    Expr::Binary(token, BinaryOp::Sub, Box::new(target), Box::new(Expr::Number(token, 1)))
+}
+
+// First pass: collects the symbols defined by the lines that are not
+// excluded by conditionals. With `relocatable` set, every section has its
+// own addresses.
+fn define_symbols<'a>(
+    program: &Program<'a>,
+    defines: &[(String, i64)],
+    relocatable: bool,
+) -> Result<(Vec<Line<'a>>, Symbols), ParserError<'a>> {
+    let mut symbols = Symbols::default();
+    for (name, value) in defines {
+        symbols.define_constant(name, "", *value);
+    }
+    symbols.sections.push(Section {
+        name: "text".to_string(),
+        data: Vec::new(),
+        align: 1,
+        relocations: Vec::new(),
+    });
+
+    let mut lines = Vec::new();
+    let mut conditionals: Vec<Conditional> = Vec::new();
+    let mut scope = String::new();
+    let mut addresses = vec![0];
+    let mut current = 0;
+
+    for items in program.items.chunk_by(|a, b| a.line == b.line) {
+        let index = lines.len();
+        let active = conditionals.iter().all(|conditional| conditional.active);
+
+        let mut labels = Vec::new();
+        let mut statement = None;
+        for item in items {
+            match &item.kind {
+                ItemKind::Label(token) => labels.push(*token),
+                ItemKind::Instruction(_) | ItemKind::Directive(_) => statement = Some(&item.kind),
+                ItemKind::Comment(_) => {}
+            }
+        }
+
+        if let Some(ItemKind::Directive(directive)) = statement {
+            let resolve = |symbol: &str| symbols.resolve(symbol, &scope, index);
+            let condition = match &directive.kind {
+                // as in the C preprocessor, names that are not defined are 0
+                DirectiveKind::If(expr) => {
+                    Some(active && expr.eval(&|symbol| resolve(symbol).or(Some(0)))? != 0)
+                }
+                DirectiveKind::Ifdef(name) => Some(active && resolve(name).is_some()),
+                DirectiveKind::Ifndef(name) => Some(active && resolve(name).is_none()),
+                _ => None,
+            };
+            match (&directive.kind, condition) {
+                (_, Some(condition)) => conditionals.push(Conditional {
+                    token: directive.token,
+                    active: condition,
+                    taken: condition || !active,
+                    in_else: false,
+                }),
+                (DirectiveKind::Else, _) => match conditionals.last_mut() {
+                    Some(conditional) if !conditional.in_else => {
+                        conditional.active = !conditional.taken;
+                        conditional.in_else = true;
                     }
                     _ => {
                         return Err(ParserError {
-                            token: &tokens[i + 1],
-                            expected: Token::EOL(0),
+                            token: directive.token,
+                            kind: ParserErrorKind::ElseWithoutIf,
                         })
                     }
                 },
-                "store" => match tokens[i + 1] {
-                    Token::EOL(_) => {
-                        i += 2;
-                        instruction += 1;
-                        mem.push(0x02);
+                (DirectiveKind::Endif, _) => {
+                    conditionals.pop().ok_or(ParserError {
+                        token: directive.token,
+                        kind: ParserErrorKind::EndifWithoutIf,
+                    })?;
+                }
+                _ => {}
+            }
+            if directive.kind.is_conditional() {
+                continue;
+            }
+        }
+        if !active {
+            continue;
+        }
+
+        let token = match (statement, labels.first()) {
+            (Some(ItemKind::Instruction(instruction)), _) => instruction.token,
+            (Some(ItemKind::Directive(directive)), _) => directive.token,
+            (_, Some(label)) => *label,
+            // lines with only a comment
+            _ => continue,
+        };
+        let mut line = Line {
+            token,
+            labels,
+            name: None,
+            statement: None,
+            scope: String::new(),
+            section: 0,
+            code: false,
+            pseudo: false,
+        };
+        if let Some(kind) = statement {
+            lower(kind, &mut line)?;
+        }
+        if let Some(label) = line.labels.iter().filter_map(|label| opens_scope(label)).next_back() {
+            scope = label.to_string();
+        }
+        line.scope = scope.clone();
+
+        if let (Some(Statement::Section(name)), true) = (&line.statement, relocatable) {
+            current = match symbols.sections.iter().position(|section| section.name == *name) {
+                Some(index) => index,
+                None => {
+                    symbols.sections.push(Section {
+                        name: name.to_string(),
+                        data: Vec::new(),
+                        align: 1,
+                        relocations: Vec::new(),
+                    });
+                    addresses.push(0);
+                    addresses.len() - 1
+                }
+            };
+        }
+        line.section = current;
+        let section = if relocatable { Some(current) } else { None };
+        let address = addresses[current] as i64;
+
+        for label in &line.labels {
+            symbols.define_label(label, &scope, index, section, address);
+        }
+        if let Some(name) = line.name {
+            symbols.define_address(name, &scope, section, address);
+        }
+
+        match &line.statement {
+            Some(Statement::Equ(name, expr)) => {
+                let value = expr.eval_value(&|symbol| symbols.value(symbol, &scope, index))?;
+                match value.base {
+                    None => symbols.define_constant(name, &scope, value.offset),
+                    Some(Target::Section(base)) => {
+                        symbols.define_constant(name, &scope, value.offset);
+                        symbols.bases.insert(qualify(name, &scope), base);
                     }
-                    _ => {
+                    Some(Target::Symbol(_)) => {
                         return Err(ParserError {
-                            token: &tokens[i + 1],
-                            expected: Token::EOL(0),
+                            token: expr.token(),
+                            kind: ParserErrorKind::NotAbsolute,
                         })
                     }
-                },
-                "push" => match &tokens[i + 1] {
-                    Token::Word(_, name) => match tokens[i + 2] {
-                        Token::EOL(_) => {
-                            i += 3;
-                            if name_table.contains_key(name) {
-                                instruction += 2;
-                                mem.push(0x03);
-                                mem.push(name_table[name]);
-                            } else {
-                                return Err(ParserError {
-                                    token: &tokens[i + 2],
-                                    expected: Token::Word(0, "unknown label".to_string()),
+                }
+            }
+            Some(Statement::Import(names)) => {
+                if !relocatable {
+                    return Err(ParserError {
+                        token: line.token,
+                        kind: ParserErrorKind::ImportOutsideObject,
+                    });
+                }
+                symbols.imports.extend(names.iter().map(|name| name.to_string()));
+            }
+            Some(statement) => {
+                let resolve = |symbol: &str| symbols.resolve(symbol, &scope, index);
+                addresses[current] = next_address(line.token, statement, addresses[current], &resolve)?;
+
+                if let (Statement::Align(expr), true) = (statement, relocatable) {
+                    let align = size_value(expr, &resolve)?;
+                    let section = &mut symbols.sections[current];
+                    section.align = section.align.max(align);
+                }
+            }
+            None => {}
+        }
+        lines.push(line);
+    }
+
+    if let Some(conditional) = conditionals.last() {
+        return Err(ParserError {
+            token: conditional.token,
+            kind: ParserErrorKind::MissingEndif,
+        });
+    }
+
+    Ok((lines, symbols))
+}
+
+// Second pass: emits the bytes of every line into its section, noting the
+// bytes that need relocating.
+fn encode<'a>(
+    lines: &[Line<'a>],
+    symbols: &Symbols,
+) -> Result<(Vec<Section>, Vec<ListingLine>), ParserError<'a>> {
+    let mut sections = symbols.sections.clone();
+    let mut listed = Vec::new();
+
+    for (index, line) in lines.iter().enumerate() {
+        let section = &mut sections[line.section];
+        let address = section.data.len();
+        let resolve = |symbol: &str| symbols.resolve(symbol, &line.scope, index);
+        match &line.statement {
+            Some(Statement::Emit(data)) => {
+                for item in data {
+                    match item {
+                        Data::Raw(byte) => section.data.push(*byte),
+                        Data::Byte(expr) => {
+                            let value = byte_value(expr, &|symbol| symbols.value(symbol, &line.scope, index))?;
+                            if let Some(target) = value.base {
+                                section.relocations.push(Relocation {
+                                    offset: section.data.len(),
+                                    target,
+                                    addend: value.offset,
                                 });
                             }
+                            section.data.push(value.offset as u8);
                         }
-                        _ => {
-                            return Err(ParserError {
-                                token: &tokens[i + 2],
-                                expected: Token::EOL(0),
-                            })
-                        }
-                    },
-                    Token::Number(_, number) => match tokens[i + 2] {
-                        Token::EOL(_) => {
-                            i += 3;
-                            instruction += 2;
-                            mem.push(0x03);
-                            mem.push(*number);
-                        }
-                        _ => {
-                            return Err(ParserError {
-                                token: &tokens[i + 2],
-                                expected: Token::EOL(0),
-                            })
-                        }
-                    },
-                    _ => {
-                        return Err(ParserError {
-                            token: &tokens[i + 1],
-                            expected: Token::Number(0, 0),
-                        })
-                    }
-                },
-                "pop" => match tokens[i + 1] {
-                    Token::EOL(_) => {
-                        i += 2;
-                        instruction += 1;
-                        mem.push(0x04);
-                    }
-                    _ => {
-                        return Err(ParserError {
-                            token: &tokens[i + 1],
-                            expected: Token::EOL(0),
-                        })
-                    }
-                },
-                "add" => match tokens[i + 1] {
-                    Token::EOL(_) => {
-                        i += 2;
-                        instruction += 1;
-                        mem.push(0x05);
-                    }
-                    _ => {
-                        return Err(ParserError {
-                            token: &tokens[i + 1],
-                            expected: Token::EOL(0),
-                        })
-                    }
-                },
-                "sub" => match tokens[i + 1] {
-                    Token::EOL(_) => {
-                        i += 2;
-                        instruction += 1;
-                        mem.push(0x06);
-                    }
-                    _ => {
-                        return Err(ParserError {
-                            token: &tokens[i + 1],
-                            expected: Token::EOL(0),
-                        })
-                    }
-                },
-                "and" => match tokens[i + 1] {
-                    Token::EOL(_) => {
-                        i += 2;
-                        instruction += 1;
-                        mem.push(0x07);
-                    }
-                    _ => {
-                        return Err(ParserError {
-                            token: &tokens[i + 1],
-                            expected: Token::EOL(0),
-                        })
-                    }
-                },
-                "or" => match tokens[i + 1] {
-                    Token::EOL(_) => {
-                        i += 2;
-                        instruction += 1;
-                        mem.push(0x08);
-                    }
-                    _ => {
-                        return Err(ParserError {
-                            token: &tokens[i + 1],
-                            expected: Token::EOL(0),
-                        })
-                    }
-                },
-                "xor" => match tokens[i + 1] {
-                    Token::EOL(_) => {
-                        i += 2;
-                        instruction += 1;
-                        mem.push(0x09);
-                    }
-                    _ => {
-                        return Err(ParserError {
-                            token: &tokens[i + 1],
-                            expected: Token::EOL(0),
-                        })
-                    }
-                },
-                "jp" => match &tokens[i + 1] {
-                    Token::Word(_, condition) => match condition.to_lowercase().as_ref() {
-                        "gt" => match tokens[i + 2] {
-                            Token::EOL(_) => {
-                                i += 3;
-                                mem.push(0x0A);
-                                mem.push(0x01);
-                                instruction += 2;
-                            }
-                            _ => {
-                                return Err(ParserError {
-                                    token: &tokens[i + 2],
-                                    expected: Token::EOL(0),
-                                })
-                            }
-                        },
-                        "lt" => match tokens[i + 2] {
-                            Token::EOL(_) => {
-                                i += 3;
-                                mem.push(0x0A);
-                                mem.push(0x02);
-                                instruction += 2;
-                            }
-                            _ => {
-                                return Err(ParserError {
-                                    token: &tokens[i + 2],
-                                    expected: Token::EOL(0),
-                                })
-                            }
-                        },
-                        "geq" => match tokens[i + 2] {
-                            Token::EOL(_) => {
-                                i += 3;
-                                mem.push(0x0A);
-                                mem.push(0x03);
-                                instruction += 2;
-                            }
-                            _ => {
-                                return Err(ParserError {
-                                    token: &tokens[i + 2],
-                                    expected: Token::EOL(0),
-                                })
-                            }
-                        },
-                        "leq" => match tokens[i + 2] {
-                            Token::EOL(_) => {
-                                i += 3;
-                                mem.push(0x0A);
-                                mem.push(0x04);
-                                instruction += 2;
-                            }
-                            _ => {
-                                return Err(ParserError {
-                                    token: &tokens[i + 2],
-                                    expected: Token::EOL(0),
-                                })
-                            }
-                        },
-                        "eq" => match tokens[i + 2] {
-                            Token::EOL(_) => {
-                                i += 3;
-                                mem.push(0x0A);
-                                mem.push(0x05);
-                                instruction += 2;
-                            }
-                            _ => {
-                                return Err(ParserError {
-                                    token: &tokens[i + 2],
-                                    expected: Token::EOL(0),
-                                })
-                            }
-                        },
-                        "neq" => match tokens[i + 2] {
-                            Token::EOL(_) => {
-                                i += 3;
-                                mem.push(0x0A);
-                                mem.push(0x06);
-                                instruction += 2;
-                            }
-                            _ => {
-                                return Err(ParserError {
-                                    token: &tokens[i + 2],
-                                    expected: Token::EOL(0),
-                                })
-                            }
-                        },
-                        _ => {
-                            return Err(ParserError {
-                                token: &tokens[i + 1],
-                                expected: Token::Word(
-                                    0,
-                                    "EOL or gt, lt, geq, leq, eq, neq".to_string(),
-                                ),
-                            })
-                        }
-                    },
-                    Token::EOL(_) => {
-                        i += 2;
-                        mem.push(0x0A);
-                        mem.push(0x00);
-                        instruction += 2;
-                    }
-                    _ => {
-                        return Err(ParserError {
-                            token: &tokens[i + 1],
-                            expected: Token::Number(0, 0),
-                        })
+                        Data::Bytes(bytes) => section.data.extend_from_slice(bytes),
                     }
-                },
-                _ => {
-                    return Err(ParserError {
-                        token,
-                        expected: Token::EOL(0),
-                    })
                 }
-            },
-            _ => {
+            }
+            Some(statement) => {
+                let next = next_address(line.token, statement, address, &resolve)?;
+                section.data.resize(next, 0);
+            }
+            None => {}
+        }
+
+        let emitted = match line.statement {
+            Some(Statement::Emit(_)) => section.data[address..].to_vec(),
+            _ => Vec::new(),
+        };
+        listed.push(ListingLine {
+            position: line.token.position(),
+            address,
+            bytes: emitted,
+            code: line.code,
+            pseudo: line.pseudo,
+        });
+    }
+
+    Ok((sections, listed))
+}
+
+// Address right after `statement` when it is placed at `address`.
+fn next_address<'a>(
+    token: &'a Token,
+    statement: &Statement<'a>,
+    address: usize,
+    resolve: &dyn Fn(&str) -> Option<i64>,
+) -> Result<usize, ParserError<'a>> {
+    let next = match statement {
+        Statement::Emit(data) => address + data.iter().map(Data::len).sum::<usize>(),
+        Statement::Reserve(expr) => address + size_value(expr, resolve)?,
+        Statement::Org(expr) => {
+            let target = size_value(expr, resolve)?;
+            if target < address {
                 return Err(ParserError {
-                    token,
-                    expected: Token::Word(0, "PUSH, POP, ADD".to_string()),
-                })
+                    token: expr.token(),
+                    kind: ParserErrorKind::OrgBackwards { address, target },
+                });
+            }
+            target
+        }
+        Statement::Align(expr) => {
+            let alignment = size_value(expr, resolve)?;
+            if alignment == 0 {
+                return Err(ParserError {
+                    token: expr.token(),
+                    kind: ParserErrorKind::ZeroAlignment,
+                });
             }
+            address.div_ceil(alignment) * alignment
         }
+        Statement::Equ(..) | Statement::Section(_) | Statement::Export(_) | Statement::Import(_) => address,
+    };
+
+    if next > MEMORY_SIZE {
+        return Err(ParserError {
+            token,
+            kind: ParserErrorKind::OutOfMemory,
+        });
+    }
+
+    Ok(next)
+}
+
+fn byte_value<'a>(expr: &Expr<'a>, resolve: &dyn Fn(&str) -> Option<Value>) -> Result<Value, ParserError<'a>> {
+    let value = expr.eval_value(resolve)?;
+    if !(-128..=255).contains(&value.offset) {
+        return Err(ParserError {
+            token: expr.token(),
+            kind: ParserErrorKind::ByteOutOfRange(value.offset),
+        });
+    }
+    Ok(value)
+}
+
+fn size_value<'a>(expr: &Expr<'a>, resolve: &dyn Fn(&str) -> Option<i64>) -> Result<usize, ParserError<'a>> {
+    let value = expr.eval(resolve)?;
+    if !(0..=MEMORY_SIZE as i64).contains(&value) {
+        return Err(ParserError {
+            token: expr.token(),
+            kind: ParserErrorKind::SizeOutOfRange(value),
+        });
     }
+    Ok(value as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(mem)
+    fn assemble(source: &str) -> Vec<u8> {
+        let tokens = tokenize(source).unwrap();
+        parse(&tokens).unwrap()
+    }
+
+    fn error(source: &str) -> ParserErrorKind {
+        let tokens = tokenize(source).unwrap();
+        parse(&tokens).unwrap_err().kind
+    }
+
+    #[test]
+    fn data_directives() {
+        let mem = assemble("push table\nhalt\norg 4\ntable: db 1, 2, 0x3\nds 2\nalign 4\ndw last 9\nequ N 7\ndb N\n");
+        assert_eq!(mem, vec![0x03, 4, 0x00, 0, 1, 2, 3, 0, 0, 0, 0, 0, 9, 7]);
+
+        let mem = assemble(".asciz \"a\\n\"\n.pstr s \"\\x41\\t\\\"\"\npush s\n");
+        assert_eq!(mem, vec![b'a', b'\n', 0, 3, b'A', b'\t', b'"', 0x03, 3]);
+        assert_eq!(assemble(".pstr \"\"\n"), vec![0]);
+
+        let long = format!(".pstr \"{}\"\n", "x".repeat(256));
+        assert_eq!(error(&long), ParserErrorKind::StringTooLong);
+        let longest = format!(".pstr \"{}\"\n", "x".repeat(255));
+        assert_eq!(assemble(&longest).len(), 256);
+    }
+
+    #[test]
+    fn named_data() {
+        let mem = assemble("push value\npush bytes\nhalt\ndw value 5\ndb bytes \"ab\", 0\n");
+        assert_eq!(mem, vec![0x03, 5, 0x03, 6, 0x00, 5, b'a', b'b', 0]);
+    }
+
+    #[test]
+    fn pseudo_instructions() {
+        assert_eq!(assemble("jmp l\nl: halt\n"), vec![0x03, 3, 0x0A, 0x00, 0x00]);
+        assert_eq!(assemble("jz l\nl: halt\n"), vec![0x03, 3, 0x0A, 0x05, 0x00]);
+        assert_eq!(
+            assemble("inc 0x40\n"),
+            vec![0x03, 0x40, 0x01, 0x03, 1, 0x05, 0x03, 0x40, 0x02]
+        );
+        assert_eq!(
+            assemble("dec 0x40\n"),
+            vec![0x03, 1, 0x03, 0x40, 0x01, 0x06, 0x03, 0x40, 0x02]
+        );
+        assert_eq!(assemble("mov 0x41, 0x40\n"), vec![0x03, 0x40, 0x01, 0x03, 0x41, 0x02]);
+        assert_eq!(assemble("not\n"), vec![0x03, 0xFF, 0x09]);
+        assert_eq!(error("inc\n"), ParserErrorKind::Expected(Token::Number(0, 0)));
+        assert_eq!(error("mov 0x41\n"), ParserErrorKind::Expected(Token::Comma(0)));
+    }
+
+    #[test]
+    fn local_labels() {
+        let mem = assemble("f: push .loop\n.loop: jmp .loop\ng: push .loop\n.loop: push f.loop\nhalt\n");
+        assert_eq!(mem, vec![0x03, 2, 0x03, 1, 0x0A, 0x00, 0x03, 8, 0x03, 2, 0x00]);
+
+        // before the first global label, local labels have an empty scope
+        assert_eq!(assemble(".x: push .x\nhalt\n"), vec![0x03, 0, 0x00]);
+        assert_eq!(error(".x: halt\nstart: push .x\n"), ParserErrorKind::UnknownSymbol);
+    }
+
+    #[test]
+    fn numeric_labels() {
+        let mem = assemble("1: push 1b\npush 1f\n1: push 1b\npush 1b\n");
+        assert_eq!(mem, vec![0x03, 0, 0x03, 4, 0x03, 4, 0x03, 4]);
+        assert_eq!(error("1: halt\npush 1f\n"), ParserErrorKind::UnknownSymbol);
+        assert_eq!(error("push 2b\n2: halt\n"), ParserErrorKind::UnknownSymbol);
+    }
+
+    #[test]
+    fn forward_references() {
+        let mem = assemble("push end - start\nstart: push SIZE * 2\nend: halt\nequ SIZE 3\n");
+        assert_eq!(mem, vec![0x03, 2, 0x03, 6, 0x00]);
+    }
+
+    #[test]
+    fn conditional_assembly() {
+        let mem = assemble(
+            "equ DEBUG 1\nif DEBUG\npush 1\nelse\npush 2\nendif\nif TRACE\npush 3\nendif\nifndef TRACE\nhalt\nendif\n",
+        );
+        assert_eq!(mem, vec![0x03, 1, 0x00]);
+        assert_eq!(assemble("if TRACE == 0\nhalt\nendif\n"), vec![0x00]);
+        assert_eq!(error("if 1\nhalt\n"), ParserErrorKind::MissingEndif);
+        assert_eq!(error("else\n"), ParserErrorKind::ElseWithoutIf);
+        assert_eq!(error("endif\n"), ParserErrorKind::EndifWithoutIf);
+    }
+
+    #[test]
+    fn directive_errors() {
+        assert_eq!(error("db FOO BAR\n"), ParserErrorKind::AmbiguousName);
+        assert_eq!(
+            error("org 8\norg 4\n"),
+            ParserErrorKind::OrgBackwards { address: 8, target: 4 }
+        );
+        assert_eq!(error("align 0\n"), ParserErrorKind::ZeroAlignment);
+        assert_eq!(error("db 256\n"), ParserErrorKind::ByteOutOfRange(256));
+        assert_eq!(error("ds 300\n"), ParserErrorKind::SizeOutOfRange(300));
+        assert_eq!(error("org 255\ndb 1, 2\n"), ParserErrorKind::OutOfMemory);
+        assert_eq!(error("frob\n"), ParserErrorKind::UnknownInstruction);
+        assert_eq!(error("push\n"), ParserErrorKind::Expected(Token::Number(0, 0)));
+    }
 }
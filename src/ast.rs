@@ -0,0 +1,546 @@
+//! Syntax tree of assembly source, built from tokens before anything is
+//! encoded. Tools like formatters and linters work on a `Program` instead of
+//! on bytes.
+
+use assembler::{condition, opcode, ParserError, ParserErrorKind, Token};
+use expr::{parse_expr, Expr};
+
+/// Source positions covered by an item. `end` is the position of the token
+/// after it, e.g. the comment or end of its line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Program<'a> {
+    pub items: Vec<Item<'a>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Item<'a> {
+    pub kind: ItemKind<'a>,
+    pub span: Span,
+    /// Index of the line in the token stream the item is on.
+    pub line: usize,
+}
+
+#[derive(Debug, Clone)]
+pub enum ItemKind<'a> {
+    /// A `name:` or numeric `1:` label.
+    Label(&'a Token),
+    Instruction(Instruction<'a>),
+    Directive(Directive<'a>),
+    /// The text after a `;`.
+    Comment(&'a str),
+}
+
+/// An instruction or pseudo-instruction.
+#[derive(Debug, Clone)]
+pub struct Instruction<'a> {
+    pub token: &'a Token,
+    /// The mnemonic in lowercase.
+    pub mnemonic: String,
+    pub operands: Vec<Operand<'a>>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Operand<'a> {
+    Expr(Expr<'a>),
+    /// The condition of `jp`, e.g. `gt`, and its encoding.
+    Condition(&'a Token, u8),
+}
+
+#[derive(Debug, Clone)]
+pub struct Directive<'a> {
+    pub token: &'a Token,
+    pub kind: DirectiveKind<'a>,
+}
+
+/// Directives, named after what they do rather than their keyword since
+/// some have several spellings, e.g. `ds` and `resb`. Data directives may
+/// name their first byte, as in `db name 1, 2`, unless the value is a symbol;
+/// `name: db value` always works.
+#[derive(Debug, Clone)]
+pub enum DirectiveKind<'a> {
+    Word { name: Option<&'a str>, value: Expr<'a> },
+    Bytes { name: Option<&'a str>, values: Vec<Data<'a>> },
+    /// A string followed by a zero byte.
+    Asciz { name: Option<&'a str>, text: &'a [u8] },
+    /// A string preceded by its length.
+    Pstr { name: Option<&'a str>, text: &'a [u8] },
+    Reserve { name: Option<&'a str>, size: Expr<'a> },
+    Org(Expr<'a>),
+    Align(Expr<'a>),
+    Equ(&'a str, Expr<'a>),
+    Section(&'a str),
+    Export(Vec<&'a Token>),
+    Import(Vec<&'a str>),
+    If(Expr<'a>),
+    Ifdef(&'a str),
+    Ifndef(&'a str),
+    Else,
+    Endif,
+}
+
+impl<'a> DirectiveKind<'a> {
+    /// Whether this is part of an `if` block rather than a statement.
+    pub fn is_conditional(&self) -> bool {
+        matches!(
+            self,
+            DirectiveKind::If(_) | DirectiveKind::Ifdef(_) | DirectiveKind::Ifndef(_) | DirectiveKind::Else | DirectiveKind::Endif
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Data<'a> {
+    Expr(Expr<'a>),
+    Str(&'a [u8]),
+}
+
+/// Parses `tokens` into a program. Every line, including the last, must end
+/// with an `EOL`.
+pub fn parse(tokens: &[Token]) -> Result<Program<'_>, ParserError<'_>> {
+    let mut program = Program::default();
+    let mut start = 0;
+    let mut line = 0;
+
+    for (i, token) in tokens.iter().enumerate() {
+        if !matches!(token, Token::EOL(_)) {
+            continue;
+        }
+        parse_line(&tokens[start..=i], line, &mut program.items)?;
+        start = i + 1;
+        line += 1;
+    }
+
+    if start < tokens.len() {
+        return Err(ParserError {
+            token: &tokens[tokens.len() - 1],
+            kind: ParserErrorKind::Expected(Token::EOL(0)),
+        });
+    }
+    Ok(program)
+}
+
+// Parses a single line; `tokens` always ends with its `EOL`.
+fn parse_line<'a>(tokens: &'a [Token], line: usize, items: &mut Vec<Item<'a>>) -> Result<(), ParserError<'a>> {
+    let eol = &tokens[tokens.len() - 1];
+    let comment = match tokens.len().checked_sub(2).map(|i| &tokens[i]) {
+        Some(token @ Token::Comment(_, text)) => Some((token, text)),
+        _ => None,
+    };
+    let end = comment.map_or(eol, |(token, _)| token);
+
+    let mut i = 0;
+    let mut labels = 0;
+    while let (Token::Word(..), Some(Token::Colon(colon))) | (Token::Number(..), Some(Token::Colon(colon))) =
+        (&tokens[i], tokens.get(i + 1))
+    {
+        items.push(Item {
+            kind: ItemKind::Label(&tokens[i]),
+            span: Span {
+                start: tokens[i].position(),
+                end: colon + 1,
+            },
+            line,
+        });
+        labels += 1;
+        i += 2;
+    }
+
+    let token = &tokens[i];
+    match token {
+        Token::Word(..) => {
+            let kind = parse_statement(tokens, &mut i)?;
+            expect_end(tokens, i)?;
+            if let (ItemKind::Directive(directive), true) = (&kind, labels > 0) {
+                if directive.kind.is_conditional() {
+                    return Err(ParserError {
+                        token,
+                        kind: ParserErrorKind::ConditionalAfterLabel,
+                    });
+                }
+            }
+            items.push(Item {
+                kind,
+                span: Span {
+                    start: token.position(),
+                    end: end.position(),
+                },
+                line,
+            });
+        }
+        Token::EOL(_) | Token::Comment(..) => {}
+        _ => {
+            return Err(ParserError {
+                token,
+                kind: ParserErrorKind::UnknownInstruction,
+            })
+        }
+    }
+
+    if let Some((token, text)) = comment {
+        items.push(Item {
+            kind: ItemKind::Comment(text),
+            span: Span {
+                start: token.position(),
+                end: eol.position(),
+            },
+            line,
+        });
+    }
+    Ok(())
+}
+
+// Parses the instruction or directive at `tokens[*i]`.
+fn parse_statement<'a>(tokens: &'a [Token], i: &mut usize) -> Result<ItemKind<'a>, ParserError<'a>> {
+    let token = &tokens[*i];
+    let word = match token {
+        Token::Word(_, word) => word.to_lowercase(),
+        _ => unreachable!(),
+    };
+    *i += 1;
+
+    let kind = match word.as_ref() {
+        "dw" => {
+            let name = optional_name(tokens, i)?;
+            DirectiveKind::Word {
+                name,
+                value: parse_expr(tokens, i)?,
+            }
+        }
+        "db" => {
+            let name = optional_name(tokens, i)?;
+            let mut values = Vec::new();
+            loop {
+                match &tokens[*i] {
+                    Token::Str(_, bytes) => {
+                        values.push(Data::Str(bytes));
+                        *i += 1;
+                    }
+                    _ => values.push(Data::Expr(parse_expr(tokens, i)?)),
+                }
+                match tokens[*i] {
+                    Token::Comma(_) => *i += 1,
+                    _ => break,
+                }
+            }
+            DirectiveKind::Bytes { name, values }
+        }
+        ".asciz" | ".pstr" => {
+            let name = optional_name(tokens, i)?;
+            let text = match &tokens[*i] {
+                Token::Str(_, bytes) => bytes,
+                _ => {
+                    return Err(ParserError {
+                        token: &tokens[*i],
+                        kind: ParserErrorKind::Expected(Token::Str(0, Vec::new())),
+                    })
+                }
+            };
+            *i += 1;
+
+            if word == ".pstr" {
+                if text.len() > u8::MAX as usize {
+                    return Err(ParserError {
+                        token: &tokens[*i - 1],
+                        kind: ParserErrorKind::StringTooLong,
+                    });
+                }
+                DirectiveKind::Pstr { name, text }
+            } else {
+                DirectiveKind::Asciz { name, text }
+            }
+        }
+        "ds" | "resb" => {
+            let name = optional_name(tokens, i)?;
+            DirectiveKind::Reserve {
+                name,
+                size: parse_expr(tokens, i)?,
+            }
+        }
+        "org" => DirectiveKind::Org(parse_expr(tokens, i)?),
+        "align" => DirectiveKind::Align(parse_expr(tokens, i)?),
+        "equ" => {
+            let name = symbol_name(tokens, i, "constant name")?;
+            DirectiveKind::Equ(name, parse_expr(tokens, i)?)
+        }
+        "section" => DirectiveKind::Section(symbol_name(tokens, i, "section name")?),
+        "export" | "global" => DirectiveKind::Export(name_list(tokens, i)?),
+        "import" | "extern" => {
+            let names = name_list(tokens, i)?.into_iter().filter_map(|token| match token {
+                Token::Word(_, name) => Some(name.as_ref()),
+                _ => None,
+            });
+            DirectiveKind::Import(names.collect())
+        }
+        "if" => DirectiveKind::If(parse_expr(tokens, i)?),
+        "ifdef" => DirectiveKind::Ifdef(symbol_name(tokens, i, "symbol name")?),
+        "ifndef" => DirectiveKind::Ifndef(symbol_name(tokens, i, "symbol name")?),
+        "else" => DirectiveKind::Else,
+        "endif" => DirectiveKind::Endif,
+        _ => {
+            return Ok(ItemKind::Instruction(Instruction {
+                token,
+                operands: parse_operands(tokens, i, &word)?,
+                mnemonic: word,
+            }))
+        }
+    };
+
+    Ok(ItemKind::Directive(Directive { token, kind }))
+}
+
+fn parse_operands<'a>(tokens: &'a [Token], i: &mut usize, mnemonic: &str) -> Result<Vec<Operand<'a>>, ParserError<'a>> {
+    match mnemonic {
+        "push" | "jmp" | "jz" | "inc" | "dec" => Ok(vec![Operand::Expr(parse_expr(tokens, i)?)]),
+        "mov" => {
+            let dst = parse_expr(tokens, i)?;
+            match tokens[*i] {
+                Token::Comma(_) => *i += 1,
+                _ => {
+                    return Err(ParserError {
+                        token: &tokens[*i],
+                        kind: ParserErrorKind::Expected(Token::Comma(0)),
+                    })
+                }
+            }
+            let src = parse_expr(tokens, i)?;
+            Ok(vec![Operand::Expr(dst), Operand::Expr(src)])
+        }
+        "not" => Ok(Vec::new()),
+        "jp" => {
+            let token = &tokens[*i];
+            let encoded = match token {
+                Token::EOL(_) | Token::Comment(..) => return Ok(Vec::new()),
+                Token::Word(_, name) => {
+                    *i += 1;
+                    condition(&name.to_lowercase())
+                }
+                _ => None,
+            };
+            match encoded {
+                Some(encoded) => Ok(vec![Operand::Condition(token, encoded)]),
+                None => Err(ParserError {
+                    token: &tokens[*i - 1],
+                    kind: ParserErrorKind::UnknownCondition,
+                }),
+            }
+        }
+        _ => match opcode(mnemonic) {
+            Some(_) => Ok(Vec::new()),
+            None => Err(ParserError {
+                token: &tokens[*i - 1],
+                kind: ParserErrorKind::UnknownInstruction,
+            }),
+        },
+    }
+}
+
+// Splits off the leading name of `db name 0x1, 0x2` style directives. A word
+// is only taken as the name when a value follows it; `db name -1` is read as
+// the expression `name - 1`. Two words in a row, as in `db FOO BAR`, could be
+// a name and a value or two values missing a comma, so they are rejected.
+fn optional_name<'a>(tokens: &'a [Token], i: &mut usize) -> Result<Option<&'a str>, ParserError<'a>> {
+    let name = match &tokens[*i] {
+        Token::Word(_, name) => name,
+        _ => return Ok(None),
+    };
+    let is_function = name.eq_ignore_ascii_case("lo") || name.eq_ignore_ascii_case("hi");
+
+    let named = match &tokens[*i + 1] {
+        Token::Word(..) => {
+            return Err(ParserError {
+                token: &tokens[*i],
+                kind: ParserErrorKind::AmbiguousName,
+            })
+        }
+        Token::Number(..) | Token::Str(..) => true,
+        Token::Operator(_, op) => op == "~" || (op == "(" && !is_function),
+        _ => false,
+    };
+    if named {
+        *i += 1;
+        Ok(Some(name))
+    } else {
+        Ok(None)
+    }
+}
+
+fn symbol_name<'a>(tokens: &'a [Token], i: &mut usize, expected: &'static str) -> Result<&'a str, ParserError<'a>> {
+    match &tokens[*i] {
+        Token::Word(_, name) => {
+            *i += 1;
+            Ok(name)
+        }
+        token => Err(ParserError {
+            token,
+            kind: ParserErrorKind::ExpectedName(expected),
+        }),
+    }
+}
+
+// Comma separated symbol names, e.g. of `export a, b`.
+fn name_list<'a>(tokens: &'a [Token], i: &mut usize) -> Result<Vec<&'a Token>, ParserError<'a>> {
+    let mut names = Vec::new();
+    loop {
+        match &tokens[*i] {
+            Token::Word(..) => names.push(&tokens[*i]),
+            token => {
+                return Err(ParserError {
+                    token,
+                    kind: ParserErrorKind::ExpectedName("symbol name"),
+                })
+            }
+        }
+        *i += 1;
+        match tokens[*i] {
+            Token::Comma(_) => *i += 1,
+            _ => return Ok(names),
+        }
+    }
+}
+
+// A statement ends at the end of its line or at a comment.
+fn expect_end(tokens: &[Token], i: usize) -> Result<(), ParserError<'_>> {
+    match tokens[i] {
+        Token::EOL(_) | Token::Comment(..) => Ok(()),
+        _ => Err(ParserError {
+            token: &tokens[i],
+            kind: ParserErrorKind::Expected(Token::EOL(0)),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::tokenize;
+
+    // Each item as its line, span and a short description.
+    fn items(source: &str) -> Vec<(usize, usize, usize, String)> {
+        let tokens = tokenize(source).unwrap();
+        let program = parse(&tokens).unwrap();
+        program
+            .items
+            .iter()
+            .map(|item| {
+                let description = match &item.kind {
+                    ItemKind::Label(Token::Word(_, name)) => format!("label {}", name),
+                    ItemKind::Label(Token::Number(_, number)) => format!("label {}", number),
+                    ItemKind::Label(token) => panic!("label {:?}", token),
+                    ItemKind::Instruction(instruction) => {
+                        format!("{} {}", instruction.mnemonic, instruction.operands.len())
+                    }
+                    ItemKind::Directive(directive) => describe(&directive.kind),
+                    ItemKind::Comment(text) => format!("comment{}", text),
+                };
+                (item.line, item.span.start, item.span.end, description)
+            })
+            .collect()
+    }
+
+    fn describe(kind: &DirectiveKind) -> String {
+        let name = |name: &Option<&str>| name.unwrap_or("-").to_string();
+        match kind {
+            DirectiveKind::Word { name: n, .. } => format!("word {}", name(n)),
+            DirectiveKind::Bytes { name: n, values } => format!("bytes {} {}", name(n), values.len()),
+            DirectiveKind::Asciz { name: n, text } => format!("asciz {} {}", name(n), text.len()),
+            DirectiveKind::Pstr { name: n, text } => format!("pstr {} {}", name(n), text.len()),
+            DirectiveKind::Reserve { name: n, .. } => format!("reserve {}", name(n)),
+            DirectiveKind::Org(_) => "org".to_string(),
+            DirectiveKind::Align(_) => "align".to_string(),
+            DirectiveKind::Equ(name, _) => format!("equ {}", name),
+            DirectiveKind::Section(name) => format!("section {}", name),
+            DirectiveKind::Export(names) => format!("export {}", names.len()),
+            DirectiveKind::Import(names) => format!("import {}", names.join(",")),
+            DirectiveKind::If(_) => "if".to_string(),
+            DirectiveKind::Ifdef(name) => format!("ifdef {}", name),
+            DirectiveKind::Ifndef(name) => format!("ifndef {}", name),
+            DirectiveKind::Else => "else".to_string(),
+            DirectiveKind::Endif => "endif".to_string(),
+        }
+    }
+
+    fn error(source: &str) -> (usize, ParserErrorKind) {
+        let tokens = tokenize(source).unwrap();
+        let err = parse(&tokens).unwrap_err();
+        (err.token().position(), err.kind)
+    }
+
+    fn item(line: usize, start: usize, end: usize, description: &str) -> (usize, usize, usize, String) {
+        (line, start, end, description.to_string())
+    }
+
+    #[test]
+    fn labels_comments_and_spans() {
+        let source = "a: b: push 1 ; both\n; only a comment\n\n1: db name 1, \"s\"\n";
+        assert_eq!(
+            items(source),
+            vec![
+                item(0, 0, 2, "label a"),
+                item(0, 3, 5, "label b"),
+                item(0, 6, 13, "push 1"),
+                item(0, 13, 19, "comment both"),
+                item(1, 20, 36, "comment only a comment"),
+                // blank lines have no tokens and are not counted
+                item(2, 38, 40, "label 1"),
+                item(2, 41, 55, "bytes name 2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn directive_kinds() {
+        let source = "DW w 1\ndb 1\n.asciz s \"ab\"\n.pstr \"abc\"\nds 2\nresb r 1\norg 4\nalign 2\nequ N 2\n\
+                      section data\nglobal a, b\nextern putc, getc\nif N\nelse\nendif\nifdef N\nendif\nifndef M\nendif\n";
+        let descriptions: Vec<String> = items(source).into_iter().map(|item| item.3).collect();
+        assert_eq!(
+            descriptions,
+            vec![
+                "word w",
+                "bytes - 1",
+                "asciz s 2",
+                "pstr - 3",
+                "reserve -",
+                "reserve r",
+                "org",
+                "align",
+                "equ N",
+                "section data",
+                "export 2",
+                "import putc,getc",
+                "if",
+                "else",
+                "endif",
+                "ifdef N",
+                "endif",
+                "ifndef M",
+                "endif",
+            ]
+        );
+        assert_eq!(items("jp gt\n")[0].3, "jp 1");
+        assert_eq!(items("jp\n")[0].3, "jp 0");
+        assert_eq!(items("mov 1, 2\n")[0].3, "mov 2");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("push 1 2\n"), (7, ParserErrorKind::Expected(Token::EOL(0))));
+        assert_eq!(error("a: if 1\n"), (3, ParserErrorKind::ConditionalAfterLabel));
+        assert_eq!(error("a: , push\n"), (3, ParserErrorKind::UnknownInstruction));
+        assert_eq!(error("frob 1\n"), (0, ParserErrorKind::UnknownInstruction));
+        assert_eq!(error("jp always\n"), (3, ParserErrorKind::UnknownCondition));
+        assert_eq!(error("equ 1 2\n"), (4, ParserErrorKind::ExpectedName("constant name")));
+        assert_eq!(error("export a,\n"), (9, ParserErrorKind::ExpectedName("symbol name")));
+        assert_eq!(error(".asciz 1\n"), (7, ParserErrorKind::Expected(Token::Str(0, Vec::new()))));
+        assert_eq!(error("db A B\n"), (3, ParserErrorKind::AmbiguousName));
+
+        // the last line must be ended, which tokenize always does
+        let tokens = vec![Token::Word(0, "halt".to_string())];
+        assert_eq!(parse(&tokens).unwrap_err().kind, ParserErrorKind::Expected(Token::EOL(0)));
+    }
+}
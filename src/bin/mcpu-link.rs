@@ -0,0 +1,85 @@
+extern crate mcpu;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use mcpu::format::Format;
+use mcpu::link::Linker;
+use mcpu::object::{Library, Object};
+
+fn usage() -> ! {
+    eprintln!("usage: mcpu-link [-o <image>] [-f <format>] [-m <map>] <object or library>...");
+    eprintln!("       mcpu-link -a <library> <object>...");
+    process::exit(2);
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(1);
+}
+
+fn read(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|err| fail(format!("cannot read {}: {}", path, err)))
+}
+
+fn write(path: &str, data: &[u8]) {
+    fs::write(path, data).unwrap_or_else(|err| fail(format!("cannot write {}: {}", path, err)));
+}
+
+fn main() {
+    let mut output = "a.bin".to_string();
+    let mut format = None;
+    let mut map = None;
+    let mut archive = None;
+    let mut inputs = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "-o" => output = args.next().unwrap_or_else(|| usage()),
+            "-f" => match args.next().as_ref().and_then(|name| Format::from_name(name)) {
+                Some(name) => format = Some(name),
+                None => usage(),
+            },
+            "-m" => map = Some(args.next().unwrap_or_else(|| usage())),
+            "-a" => archive = Some(args.next().unwrap_or_else(|| usage())),
+            _ if !arg.starts_with('-') => inputs.push(arg),
+            _ => usage(),
+        }
+    }
+    if inputs.is_empty() {
+        usage();
+    }
+
+    if let Some(path) = archive {
+        let mut library = Library::default();
+        for input in &inputs {
+            let object = Object::from_bytes(&read(input))
+                .unwrap_or_else(|err| fail(format!("{}: {}", input, err)));
+            library.objects.push(object);
+        }
+        write(&path, &library.to_bytes());
+        return;
+    }
+
+    let mut linker = Linker::new();
+    for input in &inputs {
+        let data = read(input);
+        linker = if Library::is_library(&data) {
+            linker.library(Library::from_bytes(&data).unwrap_or_else(|err| fail(format!("{}: {}", input, err))))
+        } else {
+            linker.object(Object::from_bytes(&data).unwrap_or_else(|err| fail(format!("{}: {}", input, err))))
+        };
+    }
+
+    let linked = linker.link().unwrap_or_else(|err| fail(err.to_string()));
+    let format = format
+        .or_else(|| Format::from_path(Path::new(&output)))
+        .unwrap_or(Format::Raw);
+    write(&output, &format.write(&linked.image));
+    if let Some(path) = map {
+        write(&path, linked.map.to_text().as_bytes());
+    }
+}
@@ -0,0 +1,237 @@
+use std::fmt;
+
+use device::{Device, Mapping};
+use Emulator;
+
+pub const MAX_MEMORY_SIZE: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub memory_size: usize,
+    pub stack_base: u8,
+    pub stack_limit: u8,
+    pub load_address: u8,
+    pub entry_point: u8,
+    pub running: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            memory_size: MAX_MEMORY_SIZE,
+            stack_base: (MAX_MEMORY_SIZE - 3) as u8,
+            stack_limit: 0,
+            load_address: 0,
+            entry_point: 0,
+            running: true,
+        }
+    }
+}
+
+impl Config {
+    /// Address of the program counter, the last byte of memory.
+    pub fn pc(&self) -> usize {
+        self.memory_size - 1
+    }
+
+    /// Address of the stack pointer, right below the program counter.
+    pub fn sp(&self) -> usize {
+        self.memory_size - 2
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfigError {
+    reason: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid machine configuration: {}", self.reason)
+    }
+}
+
+fn invalid<T>(reason: String) -> Result<T, ConfigError> {
+    Err(ConfigError { reason })
+}
+
+#[derive(Default)]
+pub struct EmulatorBuilder {
+    config: Config,
+    stack_base: Option<u8>,
+    devices: Vec<Mapping>,
+}
+
+impl EmulatorBuilder {
+    pub fn new() -> EmulatorBuilder {
+        EmulatorBuilder::default()
+    }
+
+    /// Sets the memory size. Unless set explicitly, the stack base follows
+    /// it to sit right below the registers.
+    pub fn memory_size(mut self, size: usize) -> EmulatorBuilder {
+        self.config.memory_size = size;
+        self
+    }
+
+    pub fn stack_base(mut self, address: u8) -> EmulatorBuilder {
+        self.stack_base = Some(address);
+        self
+    }
+
+    /// Lowest address the stack may grow into before the machine faults.
+    pub fn stack_limit(mut self, address: u8) -> EmulatorBuilder {
+        self.config.stack_limit = address;
+        self
+    }
+
+    pub fn load_address(mut self, address: u8) -> EmulatorBuilder {
+        self.config.load_address = address;
+        self
+    }
+
+    pub fn entry_point(mut self, address: u8) -> EmulatorBuilder {
+        self.config.entry_point = address;
+        self
+    }
+
+    pub fn running(mut self, running: bool) -> EmulatorBuilder {
+        self.config.running = running;
+        self
+    }
+
+    /// Maps `device` over `len` bytes of memory starting at `base`.
+    pub fn device<D: Device + 'static>(mut self, base: u8, len: usize, device: D) -> EmulatorBuilder {
+        self.devices.push(Mapping {
+            base: base as usize,
+            len,
+            device: Box::new(device),
+        });
+        self
+    }
+
+    /// Checks the configuration and builds the machine, with the program
+    /// counter and stack pointer set up as by `Emulator::reset`.
+    pub fn build(mut self) -> Result<Emulator, ConfigError> {
+        let size = self.config.memory_size;
+        if !(4..=MAX_MEMORY_SIZE).contains(&size) {
+            return invalid(format!(
+                "memory size {} is outside of 4..={}",
+                size, MAX_MEMORY_SIZE
+            ));
+        }
+
+        self.config.stack_base = match self.stack_base {
+            Some(base) => base,
+            None => (size - 3) as u8,
+        };
+
+        let config = &self.config;
+        if config.stack_base as usize >= config.sp() {
+            return invalid(format!(
+                "stack base 0x{:02x} overlaps the registers",
+                config.stack_base
+            ));
+        }
+        if config.stack_limit > config.stack_base {
+            return invalid(format!(
+                "stack limit 0x{:02x} is above the stack base 0x{:02x}",
+                config.stack_limit, config.stack_base
+            ));
+        }
+        if config.load_address as usize >= config.sp() {
+            return invalid(format!(
+                "load address 0x{:02x} is outside of memory",
+                config.load_address
+            ));
+        }
+        if config.entry_point as usize >= config.sp() {
+            return invalid(format!(
+                "entry point 0x{:02x} is outside of memory",
+                config.entry_point
+            ));
+        }
+
+        for (i, mapping) in self.devices.iter().enumerate() {
+            if mapping.len == 0 || mapping.base + mapping.len > config.sp() {
+                return invalid(format!(
+                    "device at 0x{:02x} does not fit below the registers",
+                    mapping.base
+                ));
+            }
+            let overlaps = self.devices[..i].iter().any(|other| {
+                mapping.base < other.base + other.len && other.base < mapping.base + mapping.len
+            });
+            if overlaps {
+                return invalid(format!(
+                    "device at 0x{:02x} overlaps another device",
+                    mapping.base
+                ));
+            }
+        }
+
+        Ok(Emulator::with_config(self.config, self.devices))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use device::Console;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use Fault;
+    use Outcome;
+
+    #[test]
+    fn small_machine_starts_with_registers_set() {
+        let emu = EmulatorBuilder::new().memory_size(64).entry_point(4).build().unwrap();
+        let config = emu.config();
+        assert_eq!((config.pc(), config.sp(), config.stack_base), (63, 62, 61));
+        assert_eq!(emu.memory.len(), 64);
+        assert_eq!(emu.memory[63], 4);
+        assert_eq!(emu.memory[62], 61);
+    }
+
+    #[test]
+    fn runs_without_reset() {
+        let mut emu = EmulatorBuilder::new().memory_size(32).build().unwrap();
+        emu.load(&[0x03, 0x07, 0x00]).unwrap();
+        assert_eq!(emu.run(), Outcome::Halted);
+        assert_eq!(emu.stack(), vec![7]);
+    }
+
+    #[test]
+    fn stack_limit_faults() {
+        let mut emu = EmulatorBuilder::new().stack_base(0x80).stack_limit(0x7F).build().unwrap();
+        emu.load(&[0x03, 0x01, 0x03, 0x02, 0x03, 0x03, 0x00]).unwrap();
+        assert_eq!(emu.run(), Outcome::Fault(Fault::StackOverflow));
+    }
+
+    #[test]
+    fn devices_see_writes() {
+        let console = Rc::new(RefCell::new(Console::new()));
+        let mut emu = EmulatorBuilder::new().device(0xF0, 1, console.clone()).build().unwrap();
+        // push 'h'; push 0xF0; store; halt
+        emu.load(&[0x03, b'h', 0x03, 0xF0, 0x02, 0x00]).unwrap();
+        emu.run();
+        assert_eq!(console.borrow().output, b"h");
+    }
+
+    #[test]
+    fn rejects_invalid_configurations() {
+        let builds = vec![
+            EmulatorBuilder::new().memory_size(2),
+            EmulatorBuilder::new().memory_size(512),
+            EmulatorBuilder::new().stack_base(0xFE),
+            EmulatorBuilder::new().stack_base(0x40).stack_limit(0x50),
+            EmulatorBuilder::new().memory_size(64).load_address(0x40),
+            EmulatorBuilder::new().memory_size(64).entry_point(0x3E),
+            EmulatorBuilder::new().device(0xF8, 8, Console::new()),
+            EmulatorBuilder::new().device(0x10, 4, Console::new()).device(0x12, 4, Console::new()),
+        ];
+        for builder in builds {
+            assert!(builder.build().is_err());
+        }
+    }
+}
@@ -0,0 +1,342 @@
+//! Debug info linking addresses to source lines and labels.
+//!
+//! The text format is line based. The first line is the header, the rest are
+//! records, with fields separated by single spaces:
+//!
+//! ```text
+//! mcpu-debug 1
+//! file <index> <path>
+//! line <address> <length> <code|data> <file> <line> <column>
+//! label <name> <address>
+//! const <name> <value>
+//! ```
+//!
+//! Addresses and lengths are hexadecimal, everything else decimal. `file`
+//! paths run to the end of the line. Lines and columns are 1-based, and
+//! `line` records are sorted by address.
+
+use std::fmt;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use listing::{Listing, Symbol};
+use source::SourceMap;
+
+const HEADER: &str = "mcpu-debug 1";
+
+/// The bytes of one source line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineInfo {
+    pub address: usize,
+    pub len: usize,
+    pub code: bool,
+    pub file: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl LineInfo {
+    pub fn contains(&self, address: usize) -> bool {
+        self.address <= address && address < self.address + self.len
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DebugError {
+    line: usize,
+    reason: &'static str,
+}
+
+impl fmt::Display for DebugError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid debug info at line {}: {}", self.line, self.reason)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    pub files: Vec<PathBuf>,
+    pub lines: Vec<LineInfo>,
+    pub symbols: Vec<Symbol>,
+}
+
+impl DebugInfo {
+    /// Collects debug info for the lines of `listing` that emitted bytes.
+    pub fn new(listing: &Listing, sources: &SourceMap) -> DebugInfo {
+        let mut lines = Vec::new();
+        for line in listing.lines.iter().filter(|line| !line.bytes.is_empty()) {
+            let index = sources.files.iter().rposition(|file| file.start <= line.position);
+            if let Some(file) = index {
+                let source = &sources.files[file];
+                let (number, column) = source.line_col(line.position - source.start);
+                lines.push(LineInfo {
+                    address: line.address,
+                    len: line.bytes.len(),
+                    code: line.code,
+                    file,
+                    line: number,
+                    column,
+                });
+            }
+        }
+        lines.sort_by_key(|line| line.address);
+
+        DebugInfo {
+            files: sources.files.iter().map(|file| file.path.clone()).collect(),
+            lines,
+            symbols: listing.symbols.clone(),
+        }
+    }
+
+    /// The line whose bytes include `address`.
+    pub fn line_at(&self, address: usize) -> Option<&LineInfo> {
+        self.lines.iter().find(|line| line.contains(address))
+    }
+
+    pub fn is_code(&self, address: usize) -> bool {
+        self.line_at(address).is_some_and(|line| line.code)
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<i64> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.name == name)
+            .map(|symbol| symbol.value)
+    }
+
+    /// The closest label at or before `address`, with the offset from it.
+    pub fn label_at(&self, address: usize) -> Option<(&str, usize)> {
+        self.symbols
+            .iter()
+            .filter(|symbol| symbol.label && symbol.value >= 0 && symbol.value as usize <= address)
+            .max_by_key(|symbol| symbol.value)
+            .map(|symbol| (symbol.name.as_ref(), address - symbol.value as usize))
+    }
+
+    /// First address of code emitted for a source line, e.g. for breakpoints.
+    pub fn address_of(&self, file: usize, line: usize) -> Option<usize> {
+        self.lines
+            .iter()
+            .find(|info| info.file == file && info.line == line)
+            .map(|info| info.address)
+    }
+
+    /// Formats an address as `path:line:column (label+offset)`, leaving out
+    /// the parts that are unknown.
+    pub fn describe(&self, address: usize) -> String {
+        let mut out = match self.line_at(address) {
+            Some(line) => format!(
+                "{}:{}:{}",
+                self.files[line.file].display(),
+                line.line,
+                line.column
+            ),
+            None => format!("{:02x}", address),
+        };
+        match self.label_at(address) {
+            Some((label, 0)) => write!(out, " ({})", label).unwrap(),
+            Some((label, offset)) => write!(out, " ({}+{})", label, offset).unwrap(),
+            None => {}
+        }
+        out
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "{}", HEADER).unwrap();
+        for (index, path) in self.files.iter().enumerate() {
+            writeln!(out, "file {} {}", index, path.display()).unwrap();
+        }
+        for line in &self.lines {
+            writeln!(
+                out,
+                "line {:02x} {:x} {} {} {} {}",
+                line.address,
+                line.len,
+                if line.code { "code" } else { "data" },
+                line.file,
+                line.line,
+                line.column
+            )
+            .unwrap();
+        }
+        for symbol in &self.symbols {
+            if symbol.label {
+                writeln!(out, "label {} {:02x}", symbol.name, symbol.value).unwrap();
+            } else {
+                writeln!(out, "const {} {}", symbol.name, symbol.value).unwrap();
+            }
+        }
+        out
+    }
+
+    pub fn from_text(text: &str) -> Result<DebugInfo, DebugError> {
+        let mut records = text.lines().enumerate();
+        match records.next() {
+            Some((_, HEADER)) => {}
+            _ => {
+                return Err(DebugError {
+                    line: 1,
+                    reason: "missing mcpu-debug 1 header",
+                })
+            }
+        }
+
+        let mut info = DebugInfo::default();
+        for (index, record) in records {
+            let error = |reason| DebugError {
+                line: index + 1,
+                reason,
+            };
+            let mut fields = record.splitn(2, ' ');
+            let kind = fields.next().unwrap_or("");
+            let rest = fields.next().unwrap_or("");
+
+            match kind {
+                "file" => {
+                    let mut fields = rest.splitn(2, ' ');
+                    let number = decimal(fields.next()).ok_or_else(|| error("invalid file index"))?;
+                    if number != info.files.len() {
+                        return Err(error("file indices out of order"));
+                    }
+                    match fields.next() {
+                        Some(path) if !path.is_empty() => info.files.push(PathBuf::from(path)),
+                        _ => return Err(error("missing file path")),
+                    }
+                }
+                "line" => {
+                    let fields: Vec<&str> = rest.split(' ').collect();
+                    if fields.len() != 6 {
+                        return Err(error("expected 6 fields"));
+                    }
+                    let line = LineInfo {
+                        address: hex(fields[0]).ok_or_else(|| error("invalid address"))?,
+                        len: hex(fields[1]).ok_or_else(|| error("invalid length"))?,
+                        code: match fields[2] {
+                            "code" => true,
+                            "data" => false,
+                            _ => return Err(error("expected code or data")),
+                        },
+                        file: decimal(Some(fields[3])).ok_or_else(|| error("invalid file index"))?,
+                        line: decimal(Some(fields[4])).ok_or_else(|| error("invalid line"))?,
+                        column: decimal(Some(fields[5])).ok_or_else(|| error("invalid column"))?,
+                    };
+                    if line.file >= info.files.len() {
+                        return Err(error("unknown file index"));
+                    }
+                    info.lines.push(line);
+                }
+                "label" | "const" => {
+                    let fields: Vec<&str> = rest.split(' ').collect();
+                    if fields.len() != 2 || fields[0].is_empty() {
+                        return Err(error("expected a name and a value"));
+                    }
+                    let value = if kind == "label" {
+                        hex(fields[1]).map(|value| value as i64)
+                    } else {
+                        fields[1].parse().ok()
+                    };
+                    info.symbols.push(Symbol {
+                        name: fields[0].to_string(),
+                        value: value.ok_or_else(|| error("invalid value"))?,
+                        label: kind == "label",
+                    });
+                }
+                "" => {}
+                _ => return Err(error("unknown record")),
+            }
+        }
+
+        Ok(info)
+    }
+}
+
+fn decimal(field: Option<&str>) -> Option<usize> {
+    field?.parse().ok()
+}
+
+fn hex(field: &str) -> Option<usize> {
+    usize::from_str_radix(field, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::{tokenize, Assembler};
+    use std::path::Path;
+
+    const SOURCE: &str = "start: push 5\n  push value\nhalt\nequ SIZE 3\nvalue: db 1, 2\n";
+
+    fn info() -> DebugInfo {
+        let (_, listing) = Assembler::new()
+            .assemble_with_listing(&tokenize(SOURCE).unwrap())
+            .unwrap();
+        let mut sources = SourceMap::new();
+        sources.add(Path::new("main.s"), SOURCE);
+        DebugInfo::new(&listing, &sources)
+    }
+
+    #[test]
+    fn round_trip() {
+        let info = info();
+        let text = info.to_text();
+        assert_eq!(
+            text,
+            "mcpu-debug 1\n\
+             file 0 main.s\n\
+             line 00 2 code 0 1 8\n\
+             line 02 2 code 0 2 3\n\
+             line 04 1 code 0 3 1\n\
+             line 05 2 data 0 5 8\n\
+             const SIZE 3\n\
+             label start 00\n\
+             label value 05\n"
+        );
+        assert_eq!(DebugInfo::from_text(&text).unwrap(), info);
+    }
+
+    #[test]
+    fn lookups() {
+        let info = info();
+        // the operand of `push value`
+        assert_eq!(info.line_at(3).map(|line| line.line), Some(2));
+        assert_eq!(info.label_at(3), Some(("start", 3)));
+        assert_eq!(info.describe(3), "main.s:2:3 (start+3)");
+        assert_eq!(info.describe(5), "main.s:5:8 (value)");
+        assert_eq!(info.describe(0x40), "40 (value+59)");
+        assert!(info.is_code(4) && !info.is_code(6));
+        assert_eq!(info.symbol("SIZE"), Some(3));
+        assert_eq!(info.address_of(0, 3), Some(4));
+        assert_eq!(info.address_of(0, 4), None);
+    }
+
+    #[test]
+    fn rejects_malformed_text() {
+        let error = |text: &str| {
+            let err = DebugInfo::from_text(text).unwrap_err();
+            (err.line, err.reason)
+        };
+        assert_eq!(error(""), (1, "missing mcpu-debug 1 header"));
+        assert_eq!(error("mcpu-debug 2\n"), (1, "missing mcpu-debug 1 header"));
+        assert_eq!(error("mcpu-debug 1\n\nsection text\n"), (3, "unknown record"));
+
+        let record = |record: &str| error(&format!("mcpu-debug 1\nfile 0 main.s\n{}\n", record)).1;
+        assert_eq!(record("file 2 other.s"), "file indices out of order");
+        assert_eq!(record("file x other.s"), "invalid file index");
+        assert_eq!(record("file 1"), "missing file path");
+        assert_eq!(record("line 00 2 code 0 1"), "expected 6 fields");
+        assert_eq!(record("line zz 2 code 0 1 1"), "invalid address");
+        assert_eq!(record("line 00 -1 code 0 1 1"), "invalid length");
+        assert_eq!(record("line 00 2 text 0 1 1"), "expected code or data");
+        assert_eq!(record("line 00 2 code 1 1 1"), "unknown file index");
+        assert_eq!(record("line 00 2 code 0 x 1"), "invalid line");
+        assert_eq!(record("line 00 2 code 0 1 x"), "invalid column");
+        assert_eq!(record("label start"), "expected a name and a value");
+        assert_eq!(record("label start zz"), "invalid value");
+        assert_eq!(record("const SIZE 0x3"), "invalid value");
+        assert_eq!(
+            DebugInfo::from_text("mcpu-debug 1\nlabel\n").unwrap_err().to_string(),
+            "invalid debug info at line 2: expected a name and a value"
+        );
+    }
+}
@@ -0,0 +1,72 @@
+use std::cell::RefCell;
+use std::io;
+use std::io::Write;
+use std::rc::Rc;
+
+/// A memory-mapped peripheral. Offsets are relative to the base address the
+/// device was attached at.
+pub trait Device {
+    fn read(&self, _offset: usize) -> u8 {
+        0
+    }
+
+    fn write(&mut self, offset: usize, data: u8);
+}
+
+/// Shared handle so the host can inspect a device after attaching it.
+impl<T: Device> Device for Rc<RefCell<T>> {
+    fn read(&self, offset: usize) -> u8 {
+        self.borrow().read(offset)
+    }
+
+    fn write(&mut self, offset: usize, data: u8) {
+        self.borrow_mut().write(offset, data)
+    }
+}
+
+/// Output device collecting every byte written to it, optionally echoing it
+/// to stdout.
+#[derive(Debug, Clone, Default)]
+pub struct Console {
+    pub output: Vec<u8>,
+    echo: bool,
+}
+
+impl Console {
+    pub fn new() -> Console {
+        Console {
+            output: Vec::new(),
+            echo: false,
+        }
+    }
+
+    pub fn echo() -> Console {
+        Console {
+            output: Vec::new(),
+            echo: true,
+        }
+    }
+}
+
+impl Device for Console {
+    fn write(&mut self, _offset: usize, data: u8) {
+        self.output.push(data);
+        if self.echo {
+            let mut stdout = io::stdout();
+            let _ = stdout.write_all(&[data]);
+            let _ = stdout.flush();
+        }
+    }
+}
+
+pub(crate) struct Mapping {
+    pub(crate) base: usize,
+    pub(crate) len: usize,
+    pub(crate) device: Box<dyn Device>,
+}
+
+impl Mapping {
+    pub(crate) fn contains(&self, address: usize) -> bool {
+        address >= self.base && address < self.base + self.len
+    }
+}
@@ -0,0 +1,75 @@
+const CONDITIONS: [&str; 7] = ["", "gt", "lt", "geq", "leq", "eq", "neq"];
+
+pub fn mnemonic(opcode: u8) -> Option<&'static str> {
+    match opcode {
+        0x00 => Some("halt"),
+        0x01 => Some("load"),
+        0x02 => Some("store"),
+        0x03 => Some("push"),
+        0x04 => Some("pop"),
+        0x05 => Some("add"),
+        0x06 => Some("sub"),
+        0x07 => Some("and"),
+        0x08 => Some("or"),
+        0x09 => Some("xor"),
+        0x0A => Some("jp"),
+        _ => None,
+    }
+}
+
+/// Size of the instruction in bytes, including its operand.
+pub fn instruction_length(opcode: u8) -> usize {
+    match opcode {
+        0x03 | 0x0A => 2,
+        _ => 1,
+    }
+}
+
+/// How many bytes an instruction pops off the stack and pushes onto it,
+/// or `None` for illegal opcodes. `jp` always pops its target and also
+/// pops a data byte for the conditions `gt` to `neq`.
+pub fn stack_effect(opcode: u8, operand: Option<u8>) -> Option<(usize, usize)> {
+    match opcode {
+        0x00 => Some((0, 0)),
+        0x01 => Some((1, 1)),
+        0x02 => Some((2, 0)),
+        0x03 => Some((0, 1)),
+        0x04 => Some((1, 0)),
+        0x05..=0x09 => Some((2, 1)),
+        0x0A => match operand {
+            Some(1..=6) => Some((2, 0)),
+            _ => Some((1, 0)),
+        },
+        _ => None,
+    }
+}
+
+pub fn format_instruction(opcode: u8, operand: Option<u8>) -> String {
+    let name = match mnemonic(opcode) {
+        Some(name) => name,
+        None => return format!("db 0x{:02x}", opcode),
+    };
+
+    match (opcode, operand) {
+        (0x03, Some(value)) => format!("{} 0x{:02x}", name, value),
+        (0x0A, Some(condition)) => match CONDITIONS.get(condition as usize) {
+            Some(&"") => name.to_string(),
+            Some(condition) => format!("{} {}", name, condition),
+            None => format!("{} 0x{:02x}", name, condition),
+        },
+        _ => name.to_string(),
+    }
+}
+
+/// Decodes the instruction at `address`, returning its text and length.
+pub fn disassemble(memory: &[u8], address: usize) -> (String, usize) {
+    let opcode = memory[address];
+    let length = instruction_length(opcode);
+    let operand = if length > 1 {
+        memory.get(address + 1).cloned()
+    } else {
+        None
+    };
+
+    (format_instruction(opcode, operand), length)
+}
@@ -0,0 +1,319 @@
+use assembler::{ParserError, ParserErrorKind, Token};
+use object::Target;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    Lo,
+    Hi,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Shl,
+    Shr,
+    And,
+    Or,
+    Xor,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+/// The value of an expression in an object file. Addresses are offsets from
+/// a section or an imported symbol and only become known when linking.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Value {
+    pub base: Option<Target>,
+    pub offset: i64,
+}
+
+impl Value {
+    pub fn absolute(offset: i64) -> Value {
+        Value { base: None, offset }
+    }
+}
+
+/// A constant expression, evaluated at assembly time. Every node keeps the
+/// token it was parsed from for error reporting.
+#[derive(Debug, Clone)]
+pub enum Expr<'a> {
+    Number(&'a Token, i64),
+    Symbol(&'a Token, &'a str),
+    Unary(&'a Token, UnaryOp, Box<Expr<'a>>),
+    Binary(&'a Token, BinaryOp, Box<Expr<'a>>, Box<Expr<'a>>),
+}
+
+impl<'a> Expr<'a> {
+    pub fn token(&self) -> &'a Token {
+        match self {
+            Expr::Number(token, _) => token,
+            Expr::Symbol(token, _) => token,
+            Expr::Unary(token, _, _) => token,
+            Expr::Binary(_, _, lhs, _) => lhs.token(),
+        }
+    }
+
+    /// Evaluates the expression, looking up symbols with `resolve`.
+    pub fn eval(&self, resolve: &dyn Fn(&str) -> Option<i64>) -> Result<i64, ParserError<'a>> {
+        self.eval_value(&|name| resolve(name).map(Value::absolute))
+            .map(|value| value.offset)
+    }
+
+    /// Evaluates an expression that may refer to relocatable symbols. Only
+    /// a relocatable symbol plus or minus a constant, or the difference of
+    /// two symbols with the same base, can be relocated.
+    pub fn eval_value(&self, resolve: &dyn Fn(&str) -> Option<Value>) -> Result<Value, ParserError<'a>> {
+        match self {
+            Expr::Number(_, value) => Ok(Value::absolute(*value)),
+            Expr::Symbol(token, name) => match resolve(name) {
+                Some(value) => Ok(value),
+                None => Err(error(token, ParserErrorKind::UnknownSymbol)),
+            },
+            Expr::Unary(token, op, operand) => {
+                let value = operand.eval_value(resolve)?;
+                match (op, value.base) {
+                    // addresses fit in a byte
+                    (UnaryOp::Lo, base) => Ok(Value {
+                        base,
+                        offset: value.offset & 0xff,
+                    }),
+                    (UnaryOp::Hi, Some(_)) => Ok(Value::absolute(0)),
+                    (_, Some(_)) => Err(error(token, ParserErrorKind::NotAbsolute)),
+                    (_, None) => match op {
+                        UnaryOp::Neg => value.offset.checked_neg().ok_or(error(token, ParserErrorKind::Arithmetic)),
+                        UnaryOp::Not => Ok(!value.offset),
+                        UnaryOp::Lo => Ok(value.offset & 0xff),
+                        UnaryOp::Hi => Ok((value.offset >> 8) & 0xff),
+                    }
+                    .map(Value::absolute),
+                }
+            }
+            Expr::Binary(token, op, lhs, rhs) => {
+                let lhs = lhs.eval_value(resolve)?;
+                let rhs = rhs.eval_value(resolve)?;
+                let base = match (op, lhs.base, rhs.base) {
+                    (_, None, None) => None,
+                    (BinaryOp::Add, base, None) | (BinaryOp::Add, None, base) => base,
+                    (BinaryOp::Sub, base, None) => base,
+                    (BinaryOp::Sub, Some(lhs), Some(rhs)) if lhs == rhs => None,
+                    _ => return Err(error(token, ParserErrorKind::NotAbsolute)),
+                };
+                let (lhs, rhs) = (lhs.offset, rhs.offset);
+                let value = match op {
+                    BinaryOp::Add => lhs.checked_add(rhs),
+                    BinaryOp::Sub => lhs.checked_sub(rhs),
+                    BinaryOp::Mul => lhs.checked_mul(rhs),
+                    BinaryOp::Div => lhs.checked_div(rhs),
+                    BinaryOp::Rem => lhs.checked_rem(rhs),
+                    BinaryOp::Shl if (0..32).contains(&rhs) => lhs.checked_shl(rhs as u32),
+                    BinaryOp::Shr if (0..32).contains(&rhs) => lhs.checked_shr(rhs as u32),
+                    BinaryOp::Shl | BinaryOp::Shr => None,
+                    BinaryOp::And => Some(lhs & rhs),
+                    BinaryOp::Or => Some(lhs | rhs),
+                    BinaryOp::Xor => Some(lhs ^ rhs),
+                    BinaryOp::Eq => Some((lhs == rhs) as i64),
+                    BinaryOp::Ne => Some((lhs != rhs) as i64),
+                    BinaryOp::Lt => Some((lhs < rhs) as i64),
+                    BinaryOp::Gt => Some((lhs > rhs) as i64),
+                    BinaryOp::Le => Some((lhs <= rhs) as i64),
+                    BinaryOp::Ge => Some((lhs >= rhs) as i64),
+                };
+                match value {
+                    Some(offset) => Ok(Value { base, offset }),
+                    None => Err(error(token, ParserErrorKind::Arithmetic)),
+                }
+            }
+        }
+    }
+}
+
+fn error(token: &Token, kind: ParserErrorKind) -> ParserError<'_> {
+    ParserError { token, kind }
+}
+
+fn operator(token: &Token) -> Option<&str> {
+    match token {
+        Token::Operator(_, op) => Some(op),
+        _ => None,
+    }
+}
+
+// Binary operators from loosest to tightest binding, as in C. Comparisons
+// evaluate to 1 or 0.
+const PRECEDENCE: [&[(&str, BinaryOp)]; 8] = [
+    &[("|", BinaryOp::Or)],
+    &[("^", BinaryOp::Xor)],
+    &[("&", BinaryOp::And)],
+    &[("==", BinaryOp::Eq), ("!=", BinaryOp::Ne)],
+    &[
+        ("<", BinaryOp::Lt),
+        (">", BinaryOp::Gt),
+        ("<=", BinaryOp::Le),
+        (">=", BinaryOp::Ge),
+    ],
+    &[("<<", BinaryOp::Shl), (">>", BinaryOp::Shr)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+    &[("*", BinaryOp::Mul), ("/", BinaryOp::Div), ("%", BinaryOp::Rem)],
+];
+
+/// Parses an expression starting at `tokens[*i]`, leaving `i` on the first
+/// token after it. `tokens` must end with an `EOL`.
+pub fn parse_expr<'a>(tokens: &'a [Token], i: &mut usize) -> Result<Expr<'a>, ParserError<'a>> {
+    parse_binary(tokens, i, 0)
+}
+
+fn parse_binary<'a>(
+    tokens: &'a [Token],
+    i: &mut usize,
+    level: usize,
+) -> Result<Expr<'a>, ParserError<'a>> {
+    if level == PRECEDENCE.len() {
+        return parse_unary(tokens, i);
+    }
+
+    let mut lhs = parse_binary(tokens, i, level + 1)?;
+    loop {
+        let token = &tokens[*i];
+        let op = match operator(token)
+            .and_then(|op| PRECEDENCE[level].iter().find(|entry| entry.0 == op))
+        {
+            Some(entry) => entry.1,
+            None => return Ok(lhs),
+        };
+        *i += 1;
+        let rhs = parse_binary(tokens, i, level + 1)?;
+        lhs = Expr::Binary(token, op, Box::new(lhs), Box::new(rhs));
+    }
+}
+
+fn parse_unary<'a>(tokens: &'a [Token], i: &mut usize) -> Result<Expr<'a>, ParserError<'a>> {
+    let token = &tokens[*i];
+    *i += 1;
+
+    match token {
+        Token::Number(_, value) => Ok(Expr::Number(token, *value as i64)),
+        Token::Operator(_, op) if op == "-" || op == "~" => {
+            let unary = if op == "-" { UnaryOp::Neg } else { UnaryOp::Not };
+            Ok(Expr::Unary(token, unary, Box::new(parse_unary(tokens, i)?)))
+        }
+        Token::Operator(_, op) if op == "(" => {
+            let expr = parse_expr(tokens, i)?;
+            close_paren(tokens, i)?;
+            Ok(expr)
+        }
+        Token::Word(_, name) => {
+            let function = match name.to_lowercase().as_ref() {
+                "lo" => Some(UnaryOp::Lo),
+                "hi" => Some(UnaryOp::Hi),
+                _ => None,
+            };
+            match function {
+                Some(function) if operator(&tokens[*i]) == Some("(") => {
+                    *i += 1;
+                    let argument = parse_expr(tokens, i)?;
+                    close_paren(tokens, i)?;
+                    Ok(Expr::Unary(token, function, Box::new(argument)))
+                }
+                _ => Ok(Expr::Symbol(token, name)),
+            }
+        }
+        _ => Err(ParserError {
+            token,
+            kind: ParserErrorKind::Expected(Token::Number(0, 0)),
+        }),
+    }
+}
+
+fn close_paren<'a>(tokens: &'a [Token], i: &mut usize) -> Result<(), ParserError<'a>> {
+    match operator(&tokens[*i]) {
+        Some(")") => {
+            *i += 1;
+            Ok(())
+        }
+        _ => Err(ParserError {
+            token: &tokens[*i],
+            kind: ParserErrorKind::Expected(Token::Operator(0, ")".to_string())),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::tokenize;
+
+    fn eval(source: &str) -> Result<i64, ParserErrorKind> {
+        let tokens = tokenize(source).unwrap();
+        let mut i = 0;
+        let expr = parse_expr(&tokens, &mut i).map_err(|err| err.kind)?;
+        assert!(matches!(tokens[i], Token::EOL(_)));
+        let resolve = |name: &str| if name == "SIZE" { Some(16) } else { None };
+        expr.eval(&resolve).map_err(|err| err.kind)
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3\n"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3\n"), Ok(9));
+        assert_eq!(eval("SIZE - 1 & 0x0f\n"), Ok(15));
+        assert_eq!(eval("1 << 2 + 1\n"), Ok(8));
+        assert_eq!(eval("-SIZE % 5\n"), Ok(-1));
+        assert_eq!(eval("~0 ^ 1 | 4\n"), Ok(-2));
+        assert_eq!(eval("SIZE >= 16 == 1\n"), Ok(1));
+        assert_eq!(eval("hi(0x1234) + lo(0x1234)\n"), Ok(0x12 + 0x34));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(eval("MISSING + 1\n"), Err(ParserErrorKind::UnknownSymbol));
+        assert_eq!(eval("1 / 0\n"), Err(ParserErrorKind::Arithmetic));
+        assert_eq!(eval("1 << 64\n"), Err(ParserErrorKind::Arithmetic));
+        assert_eq!(eval("65535 * 65535 * 65535 * 65535\n"), Err(ParserErrorKind::Arithmetic));
+        assert_eq!(eval("-((0 - (1 << 31)) * ((1 << 31) * 2))\n"), Err(ParserErrorKind::Arithmetic));
+        assert_eq!(
+            eval("(1 + 2\n"),
+            Err(ParserErrorKind::Expected(Token::Operator(0, ")".to_string())))
+        );
+        assert_eq!(eval("* 2\n"), Err(ParserErrorKind::Expected(Token::Number(0, 0))));
+    }
+
+    #[test]
+    fn relocatable_values() {
+        let tokens = tokenize("a + 2 - 1\n").unwrap();
+        let text = Some(Target::Section(0));
+        let resolve = |name: &str| match name {
+            "a" | "b" => Some(Value {
+                base: text.clone(),
+                offset: 4,
+            }),
+            _ => None,
+        };
+        let expr = parse_expr(&tokens, &mut 0).unwrap();
+        assert_eq!(
+            expr.eval_value(&resolve).unwrap(),
+            Value {
+                base: text.clone(),
+                offset: 5,
+            }
+        );
+
+        let tokens = tokenize("a - b\n").unwrap();
+        let expr = parse_expr(&tokens, &mut 0).unwrap();
+        assert_eq!(expr.eval_value(&resolve).unwrap(), Value::absolute(0));
+
+        let tokens = tokenize("a * 2\n").unwrap();
+        let expr = parse_expr(&tokens, &mut 0).unwrap();
+        let err = expr.eval_value(&resolve).unwrap_err();
+        assert_eq!(err.kind, ParserErrorKind::NotAbsolute);
+    }
+}
@@ -0,0 +1,369 @@
+use std::fmt;
+use std::fmt::Write;
+use std::path::Path;
+
+use builder::MAX_MEMORY_SIZE;
+
+const RECORD_LEN: usize = 16;
+
+/// Image file formats. All images start at address 0; gaps between records
+/// are read back as zeros. Images never grow past `MAX_MEMORY_SIZE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Raw,
+    IntelHex,
+    SRecord,
+    /// Hex bytes as read by Verilog's `$readmemh`.
+    ReadMemH,
+    RustArray,
+    CArray,
+}
+
+#[derive(Debug, Clone)]
+pub struct FormatError {
+    line: usize,
+    reason: &'static str,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid image at line {}: {}", self.line, self.reason)
+    }
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name.to_lowercase().as_ref() {
+            "raw" | "bin" => Some(Format::Raw),
+            "ihex" | "hex" => Some(Format::IntelHex),
+            "srec" => Some(Format::SRecord),
+            "readmemh" | "mem" => Some(Format::ReadMemH),
+            "rust" => Some(Format::RustArray),
+            "c" => Some(Format::CArray),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format from a file extension, e.g. `.hex` or `.s19`.
+    pub fn from_path(path: &Path) -> Option<Format> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_ref() {
+            "bin" | "img" => Some(Format::Raw),
+            "hex" | "ihex" => Some(Format::IntelHex),
+            "srec" | "s19" | "mot" => Some(Format::SRecord),
+            "mem" | "vmem" => Some(Format::ReadMemH),
+            "rs" => Some(Format::RustArray),
+            "c" | "h" => Some(Format::CArray),
+            _ => None,
+        }
+    }
+
+    pub fn write(self, image: &[u8]) -> Vec<u8> {
+        match self {
+            Format::Raw => image.to_vec(),
+            Format::IntelHex => write_intel_hex(image).into_bytes(),
+            Format::SRecord => write_srecord(image).into_bytes(),
+            Format::ReadMemH => write_readmemh(image).into_bytes(),
+            Format::RustArray => {
+                let header = format!("pub const IMAGE: [u8; {}] = [", image.len());
+                write_array(image, &header, "];").into_bytes()
+            }
+            Format::CArray => {
+                let header = format!("const unsigned char image[{}] = {{", image.len());
+                write_array(image, &header, "};").into_bytes()
+            }
+        }
+    }
+
+    pub fn read(self, data: &[u8]) -> Result<Vec<u8>, FormatError> {
+        match self {
+            Format::Raw => {
+                let mut image = Vec::new();
+                place(&mut image, 0, data, 1)?;
+                Ok(image)
+            }
+            Format::IntelHex => read_intel_hex(text(data)?),
+            Format::SRecord => read_srecord(text(data)?),
+            Format::ReadMemH => read_readmemh(text(data)?),
+            Format::RustArray | Format::CArray => read_array(text(data)?),
+        }
+    }
+}
+
+fn error(line: usize, reason: &'static str) -> FormatError {
+    FormatError { line, reason }
+}
+
+fn text(data: &[u8]) -> Result<&str, FormatError> {
+    std::str::from_utf8(data).map_err(|_| error(1, "not a text file"))
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+// Writes `data` at `address`, growing the image as needed.
+fn place(image: &mut Vec<u8>, address: usize, data: &[u8], line: usize) -> Result<(), FormatError> {
+    let end = match address.checked_add(data.len()) {
+        Some(end) if end <= MAX_MEMORY_SIZE => end,
+        _ => return Err(error(line, "data beyond the end of memory")),
+    };
+    if image.len() < end {
+        image.resize(end, 0);
+    }
+    image[address..end].copy_from_slice(data);
+    Ok(())
+}
+
+// Decodes a record of hex digit pairs.
+fn decode_hex(text: &str, line: usize) -> Result<Vec<u8>, FormatError> {
+    if !text.len().is_multiple_of(2) {
+        return Err(error(line, "odd number of hex digits"));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| match text.get(i..i + 2) {
+            Some(pair) => u8::from_str_radix(pair, 16).map_err(|_| error(line, "invalid hex digit")),
+            None => Err(error(line, "invalid hex digit")),
+        })
+        .collect()
+}
+
+fn write_intel_hex(image: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, chunk) in image.chunks(RECORD_LEN).enumerate() {
+        let address = i * RECORD_LEN;
+        let mut record = vec![chunk.len() as u8, (address >> 8) as u8, address as u8, 0x00];
+        record.extend_from_slice(chunk);
+        let sum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        record.push(sum.wrapping_neg());
+        writeln!(out, ":{}", hex_bytes(&record)).unwrap();
+    }
+    out.push_str(":00000001FF\n");
+    out
+}
+
+fn read_intel_hex(text: &str) -> Result<Vec<u8>, FormatError> {
+    let mut image = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if !line.starts_with(':') {
+            return Err(error(number, "record does not start with ':'"));
+        }
+        let record = decode_hex(&line[1..], number)?;
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(error(number, "wrong record length"));
+        }
+        if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err(error(number, "wrong checksum"));
+        }
+        let address = (record[1] as usize) << 8 | record[2] as usize;
+        match record[3] {
+            0x00 => place(&mut image, address, &record[4..record.len() - 1], number)?,
+            0x01 => return Ok(image),
+            _ => return Err(error(number, "unsupported record type")),
+        }
+    }
+    Err(error(text.lines().count(), "missing end of file record"))
+}
+
+fn write_srecord(image: &[u8]) -> String {
+    let mut out = String::new();
+    let mut records = vec![(0u8, 0usize, b"mcpu".to_vec())];
+    for (i, chunk) in image.chunks(RECORD_LEN).enumerate() {
+        records.push((1, i * RECORD_LEN, chunk.to_vec()));
+    }
+    records.push((9, 0, Vec::new()));
+
+    for (kind, address, data) in records {
+        let mut record = vec![(data.len() + 3) as u8, (address >> 8) as u8, address as u8];
+        record.extend_from_slice(&data);
+        let sum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        record.push(!sum);
+        writeln!(out, "S{}{}", kind, hex_bytes(&record)).unwrap();
+    }
+    out
+}
+
+fn read_srecord(text: &str) -> Result<Vec<u8>, FormatError> {
+    let mut image = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.len() < 2 || !line.starts_with('S') || !line.is_ascii() {
+            return Err(error(number, "record does not start with 'S'"));
+        }
+        let record = decode_hex(&line[2..], number)?;
+        if record.len() < 4 || record.len() != record[0] as usize + 1 {
+            return Err(error(number, "wrong record length"));
+        }
+        if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0xFF {
+            return Err(error(number, "wrong checksum"));
+        }
+        let address = (record[1] as usize) << 8 | record[2] as usize;
+        match &line[..2] {
+            "S0" | "S5" => {}
+            "S1" => place(&mut image, address, &record[3..record.len() - 1], number)?,
+            "S9" => return Ok(image),
+            _ => return Err(error(number, "unsupported record type")),
+        }
+    }
+    Err(error(text.lines().count(), "missing termination record"))
+}
+
+fn write_readmemh(image: &[u8]) -> String {
+    let mut out = String::from("@00\n");
+    for chunk in image.chunks(RECORD_LEN) {
+        let bytes: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        writeln!(out, "{}", bytes.join(" ")).unwrap();
+    }
+    out
+}
+
+fn read_readmemh(text: &str) -> Result<Vec<u8>, FormatError> {
+    let mut image = Vec::new();
+    let mut address = 0;
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let line = match line.find("//") {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        for word in line.split_whitespace() {
+            if let Some(target) = word.strip_prefix('@') {
+                address = usize::from_str_radix(target, 16).map_err(|_| error(number, "invalid address"))?;
+                continue;
+            }
+            let byte = u8::from_str_radix(word, 16).map_err(|_| error(number, "invalid byte"))?;
+            place(&mut image, address, &[byte], number)?;
+            address += 1;
+        }
+    }
+    Ok(image)
+}
+
+fn write_array(image: &[u8], header: &str, footer: &str) -> String {
+    let mut out = format!("{}\n", header);
+    for chunk in image.chunks(RECORD_LEN / 2) {
+        let bytes: Vec<String> = chunk.iter().map(|byte| format!("0x{:02x},", byte)).collect();
+        writeln!(out, "    {}", bytes.join(" ")).unwrap();
+    }
+    out.push_str(footer);
+    out.push('\n');
+    out
+}
+
+// Reads the numbers between the brackets or braces after the `=`.
+fn read_array(text: &str) -> Result<Vec<u8>, FormatError> {
+    let line_of = |offset: usize| text[..offset].lines().count().max(1);
+
+    let start = text
+        .find('=')
+        .and_then(|equals| text[equals..].find(['[', '{']).map(|open| equals + open + 1))
+        .ok_or_else(|| error(1, "missing array literal"))?;
+    let end = text[start..]
+        .find([']', '}'])
+        .map(|close| start + close)
+        .ok_or_else(|| error(line_of(start), "unterminated array literal"))?;
+
+    let mut image = Vec::new();
+    let mut offset = start;
+    for item in text[start..end].split(',') {
+        let value = item.trim();
+        if !value.is_empty() {
+            let parsed = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+                Some(digits) => u8::from_str_radix(digits, 16),
+                None => value.parse(),
+            };
+            let byte = parsed.map_err(|_| error(line_of(offset), "invalid byte"))?;
+            let address = image.len();
+            place(&mut image, address, &[byte], line_of(offset))?;
+        }
+        offset += item.len() + 1;
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [Format; 6] = [
+        Format::Raw,
+        Format::IntelHex,
+        Format::SRecord,
+        Format::ReadMemH,
+        Format::RustArray,
+        Format::CArray,
+    ];
+
+    fn reason(format: Format, text: &str) -> &'static str {
+        format.read(text.as_bytes()).unwrap_err().reason
+    }
+
+    #[test]
+    fn round_trip() {
+        let full: Vec<u8> = (0..=255).collect();
+        for format in FORMATS.iter() {
+            for image in [&[][..], &[0x03, 0x05, 0x00][..], &full[..]].iter() {
+                let written = format.write(image);
+                assert_eq!(format.read(&written).unwrap(), image.to_vec(), "{:?}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn fills_gaps_with_zeros() {
+        let image = Format::ReadMemH.read(b"@02 aa // comment\n@00 01\n").unwrap();
+        assert_eq!(image, vec![0x01, 0, 0xaa]);
+        let image = Format::IntelHex.read(b":0100030011EB\n:00000001FF\n").unwrap();
+        assert_eq!(image, vec![0, 0, 0, 0x11]);
+    }
+
+    #[test]
+    fn rejects_data_beyond_memory() {
+        let err = Format::ReadMemH.read(b"00\n@FFFFFFFF 01\n").unwrap_err();
+        assert_eq!((err.line, err.reason), (2, "data beyond the end of memory"));
+        assert_eq!(reason(Format::ReadMemH, "@ffffffffffffffff 01\n"), "data beyond the end of memory");
+        assert_eq!(reason(Format::ReadMemH, "@100 01\n"), "data beyond the end of memory");
+        assert_eq!(reason(Format::IntelHex, ":01FFFF000100\n:00000001FF\n"), "data beyond the end of memory");
+        assert_eq!(reason(Format::SRecord, "S104FFFF01FC\nS9030000FC\n"), "data beyond the end of memory");
+
+        let big = vec![0; MAX_MEMORY_SIZE + 1];
+        assert!(Format::Raw.read(&big).is_err());
+        let array = Format::CArray.write(&big);
+        assert_eq!(Format::CArray.read(&array).unwrap_err().reason, "data beyond the end of memory");
+    }
+
+    #[test]
+    fn rejects_malformed_records() {
+        assert_eq!(reason(Format::IntelHex, "0000000000\n"), "record does not start with ':'");
+        assert_eq!(reason(Format::IntelHex, ":0100000011\n"), "wrong record length");
+        assert_eq!(reason(Format::IntelHex, ":01000000110F\n"), "wrong checksum");
+        assert_eq!(reason(Format::IntelHex, ":0100000G11EE\n"), "invalid hex digit");
+        assert_eq!(reason(Format::IntelHex, ":020000040000FA\n"), "unsupported record type");
+        assert_eq!(reason(Format::IntelHex, ":0100000011EE\n"), "missing end of file record");
+
+        assert_eq!(reason(Format::SRecord, "X1040000110F\n"), "record does not start with 'S'");
+        assert_eq!(reason(Format::SRecord, "S10500001100\n"), "wrong record length");
+        assert_eq!(reason(Format::SRecord, "S1040000110A\n"), "wrong checksum");
+        assert_eq!(reason(Format::SRecord, "S1040000110\n"), "odd number of hex digits");
+        assert_eq!(reason(Format::SRecord, "S204000011EA\n"), "unsupported record type");
+        assert_eq!(reason(Format::SRecord, "S104000011EA\n"), "missing termination record");
+
+        assert_eq!(reason(Format::ReadMemH, "@zz\n"), "invalid address");
+        assert_eq!(reason(Format::ReadMemH, "100\n"), "invalid byte");
+
+        assert_eq!(reason(Format::RustArray, "const IMAGE: u8;"), "missing array literal");
+        assert_eq!(reason(Format::RustArray, "const IMAGE = [1, 2"), "unterminated array literal");
+        assert_eq!(reason(Format::CArray, "x = { 1, 0x1g };"), "invalid byte");
+        assert_eq!(Format::IntelHex.read(&[0xff]).unwrap_err().reason, "not a text file");
+    }
+}
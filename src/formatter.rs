@@ -0,0 +1,209 @@
+//! Reprints assembly source in one consistent style:
+//!
+//! ```text
+//! ; comments on a line of their own stay where they are
+//! loop:
+//!     push    value + 1               ; trailing comment
+//!     jp      neq
+//! ```
+//!
+//! Labels get a line of their own, mnemonics are lowercase and indented,
+//! operands start in one column and trailing comments in another. Runs of
+//! blank lines shrink to one. Operands keep their spelling, e.g. `0x1F` or
+//! `'a'`, but are spaced evenly. Formatting is done per line on the lexemes,
+//! so macros and includes are left as they are, and formatting formatted
+//! source changes nothing.
+
+use assembler::TokenizerError;
+use lexer::{Kind, Lexeme, Lexer};
+
+const INDENT: usize = 4;
+const OPERAND_COLUMN: usize = 12;
+const COMMENT_COLUMN: usize = 32;
+
+/// Formats `source`. Lines end with `\n`, whatever they ended with before.
+pub fn format_source(source: &str) -> Result<String, TokenizerError> {
+    let mut out = String::new();
+    let mut blank = false;
+    let mut offset = 0;
+
+    for line in source.split('\n') {
+        let lexemes: Result<Vec<Lexeme>, TokenizerError> = Lexer::new(line)
+            .filter(|lexeme| !matches!(lexeme, Ok(Lexeme { kind: Kind::Newline, .. })))
+            .collect();
+        let lexemes = lexemes.map_err(|err| TokenizerError {
+            pattern: err.pattern,
+            position: offset + err.position,
+        })?;
+        offset += line.len() + 1;
+
+        if lexemes.is_empty() {
+            blank = !out.is_empty();
+            continue;
+        }
+        if blank {
+            out.push('\n');
+            blank = false;
+        }
+        format_line(&lexemes, &mut out);
+    }
+    Ok(out)
+}
+
+fn format_line(lexemes: &[Lexeme], out: &mut String) {
+    let (lexemes, comment) = match lexemes.split_last() {
+        Some((last, rest)) if last.kind == Kind::Comment => (rest, Some(last)),
+        _ => (lexemes, None),
+    };
+
+    let mut i = 0;
+    while i + 1 < lexemes.len()
+        && matches!(lexemes[i].kind, Kind::Word | Kind::Number(_))
+        && lexemes[i + 1].kind == Kind::Colon
+    {
+        let label = &lexemes[i];
+        let mut text = format!("{}:", label.text);
+        i += 2;
+        // a trailing comment goes with the statement, or the last label
+        if i == lexemes.len() {
+            if let Some(comment) = comment {
+                append_comment(&mut text, comment);
+            }
+        }
+        push_line(out, &text);
+    }
+
+    let statement = &lexemes[i..];
+    let mut text = match statement.split_first() {
+        Some((mnemonic, operands)) => {
+            let mnemonic = mnemonic.text.to_lowercase();
+            let mut text = format!("{:indent$}{}", "", mnemonic, indent = INDENT);
+            let mut operands = format_operands(operands);
+            // conditions are keywords too
+            if mnemonic == "jp" {
+                operands = operands.to_lowercase();
+            }
+            if !operands.is_empty() {
+                pad(&mut text, OPERAND_COLUMN);
+                text.push_str(&operands);
+            }
+            text
+        }
+        None => {
+            // comment lines keep their indentation, if any
+            if let (Some(comment), 0) = (comment, i) {
+                let indent = if comment.span.start > 0 { INDENT } else { 0 };
+                push_line(out, &format!("{:indent$}{}", "", comment.text, indent = indent));
+            }
+            return;
+        }
+    };
+    if let Some(comment) = comment {
+        append_comment(&mut text, comment);
+    }
+    push_line(out, &text);
+}
+
+// Joins operands with a space after commas and around binary operators.
+fn format_operands(lexemes: &[Lexeme]) -> String {
+    let mut text = String::new();
+    // whether the previous lexeme ended an operand, so an operator after it
+    // is binary
+    let mut after_operand = false;
+    // whether the next lexeme follows without a space
+    let mut attach = true;
+
+    for (i, lexeme) in lexemes.iter().enumerate() {
+        let (space, attach_next, ends_operand) = match (lexeme.kind, lexeme.text) {
+            (Kind::Comma, _) | (Kind::Colon, _) => (false, false, false),
+            (Kind::Operator, "(") => (i == 0 || !is_function(&lexemes[i - 1]), true, false),
+            (Kind::Operator, ")") => (false, false, true),
+            (Kind::Operator, _) if after_operand => (true, false, false),
+            // unary operators stick to their operand
+            (Kind::Operator, _) => (true, true, false),
+            _ => (true, false, true),
+        };
+        if space && !attach {
+            text.push(' ');
+        }
+        text.push_str(lexeme.text);
+        attach = attach_next;
+        after_operand = ends_operand;
+    }
+    text
+}
+
+fn is_function(lexeme: &Lexeme) -> bool {
+    lexeme.kind == Kind::Word && (lexeme.text.eq_ignore_ascii_case("lo") || lexeme.text.eq_ignore_ascii_case("hi"))
+}
+
+fn append_comment(text: &mut String, comment: &Lexeme) {
+    if !text.is_empty() {
+        pad(text, COMMENT_COLUMN);
+    }
+    text.push_str(comment.text);
+}
+
+// Pads `text` with spaces up to `column`, or adds a single space if it is
+// already past it.
+fn pad(text: &mut String, column: usize) {
+    let len = text.chars().count();
+    if len < column {
+        text.push_str(&" ".repeat(column - len));
+    } else {
+        text.push(' ');
+    }
+}
+
+fn push_line(out: &mut String, text: &str) {
+    out.push_str(text);
+    out.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSY: &str = "; header\r\n\r\n\r\nLOOP: start:PUSH value+1;  count \n\t  ; inner\n  JP NEQ\n\
+                         db \"a, b\" ,'c',-1,( 2 * 3 )\npush hi( x ) + ~1\n\n\nhalt";
+
+    const TIDY: &str = "\
+; header
+
+LOOP:
+start:
+    push    value + 1           ;  count
+    ; inner
+    jp      neq
+    db      \"a, b\", 'c', -1, (2 * 3)
+    push    hi(x) + ~1
+
+    halt
+";
+
+    #[test]
+    fn formats_source() {
+        assert_eq!(format_source(MESSY).unwrap(), TIDY);
+    }
+
+    #[test]
+    fn is_idempotent() {
+        assert_eq!(format_source(TIDY).unwrap(), TIDY);
+        let long = "reallyLongLabelName: push someVeryLongOperandName + 1 ; note\n";
+        let once = format_source(long).unwrap();
+        assert_eq!(format_source(&once).unwrap(), once);
+    }
+
+    #[test]
+    fn label_comments_and_blank_lines() {
+        let formatted = format_source("\n\nend: ; done\n\n").unwrap();
+        assert_eq!(formatted, format!("end:{:28}; done\n", ""));
+        assert_eq!(format_source("").unwrap(), "");
+    }
+
+    #[test]
+    fn reports_positions_in_the_whole_source() {
+        let err = format_source("push 1\npush $\n").unwrap_err();
+        assert_eq!((err.position(), err.pattern.as_str()), (12, "$"));
+    }
+}
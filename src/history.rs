@@ -0,0 +1,84 @@
+use std::collections::VecDeque;
+
+use trace::MemoryWrite;
+
+/// State needed to undo a single executed instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndoStep {
+    pub cycles: u64,
+    pub steps: u64,
+    pub writes: Vec<MemoryWrite>,
+}
+
+impl UndoStep {
+    pub fn writes_to(&self, address: usize) -> bool {
+        self.writes
+            .iter()
+            .any(|write| write.address as usize == address)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    steps: VecDeque<UndoStep>,
+    limit: Option<usize>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            steps: VecDeque::new(),
+            limit: None,
+        }
+    }
+
+    /// Keeps at most `limit` steps, dropping the oldest ones first.
+    pub fn with_limit(limit: usize) -> History {
+        History {
+            steps: VecDeque::new(),
+            limit: Some(limit),
+        }
+    }
+
+    pub fn record(&mut self, step: UndoStep) {
+        if let Some(limit) = self.limit {
+            if limit == 0 {
+                return;
+            }
+            while self.steps.len() >= limit {
+                self.steps.pop_front();
+            }
+        }
+        self.steps.push_back(step);
+    }
+
+    pub fn pop(&mut self) -> Option<UndoStep> {
+        self.steps.pop_back()
+    }
+
+    /// The oldest recorded step.
+    pub fn first(&self) -> Option<&UndoStep> {
+        self.steps.front()
+    }
+
+    pub fn last(&self) -> Option<&UndoStep> {
+        self.steps.back()
+    }
+
+    /// Recorded steps, oldest first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &UndoStep> {
+        self.steps.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.steps.clear();
+    }
+}
@@ -0,0 +1,349 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+
+use builder::Config;
+use debug::DebugInfo;
+use listing::Symbol;
+use reader::{Reader, UnexpectedEnd};
+use LoadError;
+
+const MAGIC: &[u8; 4] = b"MCPI";
+const VERSION: u8 = 1;
+
+const SECTION_SYMBOLS: u8 = 1;
+const SECTION_DEBUG: u8 = 2;
+
+/// The machine an image was built for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub memory_size: usize,
+}
+
+impl Profile {
+    /// Whether a machine with `config` can run programs for this profile.
+    /// The memory size must match, since it decides where the registers are.
+    pub fn runs_on(&self, config: &Config) -> bool {
+        self.memory_size == config.memory_size
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes", self.memory_size)
+    }
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    Invalid(&'static str),
+    UnsupportedVersion(u8),
+    ChecksumMismatch,
+    /// A field is too long for its length prefix when encoding.
+    TooLarge(&'static str),
+    Incompatible { image: Profile, machine: Profile },
+    Load(LoadError),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Io(err) => write!(f, "{}", err),
+            ImageError::Invalid(reason) => write!(f, "invalid image: {}", reason),
+            ImageError::UnsupportedVersion(version) => {
+                write!(f, "unsupported image version {}", version)
+            }
+            ImageError::ChecksumMismatch => write!(f, "image checksum does not match"),
+            ImageError::TooLarge(what) => write!(f, "{} is too large for an image", what),
+            ImageError::Incompatible { image, machine } => write!(
+                f,
+                "image built for {} cannot run on a machine with {}",
+                image, machine
+            ),
+            ImageError::Load(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<io::Error> for ImageError {
+    fn from(err: io::Error) -> ImageError {
+        ImageError::Io(err)
+    }
+}
+
+impl From<UnexpectedEnd> for ImageError {
+    fn from(_: UnexpectedEnd) -> ImageError {
+        ImageError::Invalid("unexpected end of data")
+    }
+}
+
+impl From<LoadError> for ImageError {
+    fn from(err: LoadError) -> ImageError {
+        ImageError::Load(err)
+    }
+}
+
+/// An executable program with the metadata needed to run it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub profile: Profile,
+    pub load_address: u8,
+    pub entry_point: u8,
+    pub code: Vec<u8>,
+    pub symbols: Option<Vec<Symbol>>,
+    pub debug: Option<DebugInfo>,
+}
+
+impl Image {
+    /// An image of `code` for the default machine, loaded and started at 0.
+    pub fn new(code: Vec<u8>) -> Image {
+        Image {
+            profile: Profile {
+                memory_size: Config::default().memory_size,
+            },
+            load_address: 0,
+            entry_point: 0,
+            code,
+            symbols: None,
+            debug: None,
+        }
+    }
+
+    /// Encodes the image as `MCPI` and a version byte, followed by the
+    /// memory size (u16), load address, entry point, the code (u16 length),
+    /// optional sections (kind byte, u32 length and payload) and a CRC-32 of
+    /// everything before it. Numbers are little endian. Fails if a symbol
+    /// name is longer than 255 bytes or the code longer than 65535.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ImageError> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.extend_from_slice(&fit::<u16>(self.profile.memory_size, "memory size")?.to_le_bytes());
+        out.push(self.load_address);
+        out.push(self.entry_point);
+        out.extend_from_slice(&fit::<u16>(self.code.len(), "code")?.to_le_bytes());
+        out.extend_from_slice(&self.code);
+
+        let mut sections = Vec::new();
+        if let Some(symbols) = &self.symbols {
+            let mut payload = Vec::new();
+            for symbol in symbols {
+                payload.push(fit::<u8>(symbol.name.len(), "symbol name")?);
+                payload.extend_from_slice(symbol.name.as_bytes());
+                payload.extend_from_slice(&symbol.value.to_le_bytes());
+                payload.push(symbol.label as u8);
+            }
+            sections.push((SECTION_SYMBOLS, payload));
+        }
+        if let Some(debug) = &self.debug {
+            sections.push((SECTION_DEBUG, debug.to_text().into_bytes()));
+        }
+        out.push(fit::<u8>(sections.len(), "section table")?);
+        for (kind, payload) in sections {
+            out.push(kind);
+            out.extend_from_slice(&fit::<u32>(payload.len(), "section")?.to_le_bytes());
+            out.extend_from_slice(&payload);
+        }
+
+        let checksum = crc32(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+        Ok(out)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Image, ImageError> {
+        if data.len() < 5 || &data[..4] != MAGIC {
+            return Err(ImageError::Invalid("missing MCPI header"));
+        }
+        if data[4] != VERSION {
+            return Err(ImageError::UnsupportedVersion(data[4]));
+        }
+        if data.len() < 9 {
+            return Err(ImageError::Invalid("unexpected end of data"));
+        }
+        let (body, checksum) = data.split_at(data.len() - 4);
+        if crc32(body).to_le_bytes() != checksum {
+            return Err(ImageError::ChecksumMismatch);
+        }
+
+        let mut reader = Reader::new(body, 5);
+        let memory_size = reader.u16()? as usize;
+        let load_address = reader.byte()?;
+        let entry_point = reader.byte()?;
+        let len = reader.u16()? as usize;
+        let code = reader.take(len)?.to_vec();
+
+        let mut image = Image {
+            profile: Profile { memory_size },
+            load_address,
+            entry_point,
+            code,
+            symbols: None,
+            debug: None,
+        };
+
+        for _ in 0..reader.byte()? {
+            let kind = reader.byte()?;
+            let len = reader.u32()? as usize;
+            let payload = reader.take(len)?;
+            match kind {
+                SECTION_SYMBOLS => image.symbols = Some(read_symbols(payload)?),
+                SECTION_DEBUG => {
+                    let text = String::from_utf8_lossy(payload);
+                    match DebugInfo::from_text(&text) {
+                        Ok(debug) => image.debug = Some(debug),
+                        Err(_) => return Err(ImageError::Invalid("malformed debug section")),
+                    }
+                }
+                // sections from newer assemblers are skipped
+                _ => {}
+            }
+        }
+        if !reader.is_at_end() {
+            return Err(ImageError::Invalid("trailing data"));
+        }
+
+        Ok(image)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), ImageError> {
+        writer.write_all(&self.to_bytes()?)?;
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Image, ImageError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Image::from_bytes(&data)
+    }
+}
+
+fn read_symbols(payload: &[u8]) -> Result<Vec<Symbol>, ImageError> {
+    let mut reader = Reader::new(payload, 0);
+    let mut symbols = Vec::new();
+    while !reader.is_at_end() {
+        let len = reader.byte()? as usize;
+        let name = match String::from_utf8(reader.take(len)?.to_vec()) {
+            Ok(name) => name,
+            Err(_) => return Err(ImageError::Invalid("invalid symbol name")),
+        };
+        symbols.push(Symbol {
+            name,
+            value: reader.i64()?,
+            label: reader.byte()? != 0,
+        });
+    }
+    Ok(symbols)
+}
+
+// Converts a length to the width of its field.
+fn fit<T: TryFrom<usize>>(len: usize, what: &'static str) -> Result<T, ImageError> {
+    T::try_from(len).map_err(|_| ImageError::TooLarge(what))
+}
+
+// CRC-32 as used by zip and PNG.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use builder::EmulatorBuilder;
+    use std::path::PathBuf;
+
+    fn image() -> Image {
+        let mut image = Image::new(vec![0x03, 0x05, 0x00]);
+        image.load_address = 0x10;
+        image.entry_point = 0x10;
+        image.symbols = Some(vec![Symbol {
+            name: "start".to_string(),
+            value: 0x10,
+            label: true,
+        }]);
+        image.debug = Some(DebugInfo {
+            files: vec![PathBuf::from("main.s")],
+            ..DebugInfo::default()
+        });
+        image
+    }
+
+    fn invalid(data: &[u8]) -> &'static str {
+        match Image::from_bytes(data) {
+            Err(ImageError::Invalid(reason)) => reason,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    // Replaces the checksum after changing the body of an encoded image.
+    fn resealed(mut body: Vec<u8>) -> Vec<u8> {
+        let checksum = crc32(&body);
+        body.extend_from_slice(&checksum.to_le_bytes());
+        body
+    }
+
+    #[test]
+    fn round_trip() {
+        let image = image();
+        assert_eq!(Image::from_bytes(&image.to_bytes().unwrap()).unwrap(), image);
+
+        let mut bytes = Vec::new();
+        Image::new(Vec::new()).write_to(&mut bytes).unwrap();
+        assert_eq!(Image::read_from(&mut &bytes[..]).unwrap(), Image::new(Vec::new()));
+    }
+
+    #[test]
+    fn rejects_malformed_images() {
+        let bytes = image().to_bytes().unwrap();
+        let body = bytes[..bytes.len() - 4].to_vec();
+
+        assert_eq!(invalid(b"MCPX\x01"), "missing MCPI header");
+        assert!(matches!(Image::from_bytes(b"MCPI\x07"), Err(ImageError::UnsupportedVersion(7))));
+        assert_eq!(invalid(b"MCPI\x01"), "unexpected end of data");
+
+        let mut corrupt = bytes.clone();
+        corrupt[12] ^= 1;
+        assert!(matches!(Image::from_bytes(&corrupt), Err(ImageError::ChecksumMismatch)));
+
+        assert_eq!(invalid(&resealed(body[..body.len() - 1].to_vec())), "unexpected end of data");
+        assert_eq!(invalid(&resealed([&body[..], &[0]].concat())), "trailing data");
+    }
+
+    #[test]
+    fn skips_unknown_sections() {
+        let bytes = Image::new(vec![0x00]).to_bytes().unwrap();
+        let mut body = bytes[..bytes.len() - 5].to_vec();
+        body.extend_from_slice(&[1, 9, 2, 0, 0, 0, 0xAA, 0xBB]);
+        assert_eq!(Image::from_bytes(&resealed(body)).unwrap(), Image::new(vec![0x00]));
+    }
+
+    #[test]
+    fn rejects_fields_too_large_to_encode() {
+        let mut image = image();
+        image.symbols.as_mut().unwrap()[0].name = "x".repeat(256);
+        assert!(matches!(image.to_bytes(), Err(ImageError::TooLarge("symbol name"))));
+
+        let image = Image::new(vec![0; 0x10000]);
+        assert!(matches!(image.to_bytes(), Err(ImageError::TooLarge("code"))));
+    }
+
+    #[test]
+    fn loads_on_compatible_machines() {
+        let mut emu = EmulatorBuilder::new().build().unwrap();
+        emu.load_image(&image()).unwrap();
+        assert_eq!(emu.read(0x11), 0x05);
+
+        let mut small = EmulatorBuilder::new().memory_size(64).build().unwrap();
+        let err = small.load_image(&image()).unwrap_err();
+        assert_eq!(err.to_string(), "image built for 256 bytes cannot run on a machine with 64 bytes");
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use assembler::Token;
+use lexer::{Kind, Lexeme, Lexer};
+use source::SourceMap;
+
+pub trait FileSystem {
+    fn read_to_string(&self, path: &Path) -> io::Result<String>;
+
+    fn exists(&self, path: &Path) -> bool;
+}
+
+/// Reads sources from disk.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealFileSystem;
+
+impl FileSystem for RealFileSystem {
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+}
+
+/// Serves sources from memory, e.g. for tests.
+#[derive(Debug, Clone, Default)]
+pub struct VirtualFileSystem {
+    files: HashMap<PathBuf, String>,
+}
+
+impl VirtualFileSystem {
+    pub fn new() -> VirtualFileSystem {
+        VirtualFileSystem::default()
+    }
+
+    pub fn add<P: AsRef<Path>>(&mut self, path: P, source: &str) {
+        self.files.insert(normalize(path.as_ref()), source.to_string());
+    }
+}
+
+impl FileSystem for VirtualFileSystem {
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        match self.files.get(&normalize(path)) {
+            Some(source) => Ok(source.clone()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "file not found")),
+        }
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files.contains_key(&normalize(path))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IncludeError {
+    message: String,
+    location: Option<String>,
+}
+
+impl fmt::Display for IncludeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{}: {}", location, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Loaded {
+    pub tokens: Vec<Token>,
+    pub sources: SourceMap,
+}
+
+/// Tokenizes a source file and every file it pulls in with
+/// `include "path"`. Includes are looked up relative to the including file
+/// first, then in each include path in order.
+pub struct Loader<F: FileSystem> {
+    fs: F,
+    include_paths: Vec<PathBuf>,
+}
+
+impl<F: FileSystem> Loader<F> {
+    pub fn new(fs: F) -> Loader<F> {
+        Loader {
+            fs,
+            include_paths: Vec::new(),
+        }
+    }
+
+    pub fn include_path<P: Into<PathBuf>>(mut self, path: P) -> Loader<F> {
+        self.include_paths.push(path.into());
+        self
+    }
+
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> Result<Loaded, IncludeError> {
+        let mut loaded = Loaded {
+            tokens: Vec::new(),
+            sources: SourceMap::new(),
+        };
+        let mut stack = Vec::new();
+        self.load_file(&normalize(path.as_ref()), None, &mut stack, &mut loaded)?;
+        Ok(loaded)
+    }
+
+    fn load_file(
+        &self,
+        path: &Path,
+        include: Option<usize>,
+        stack: &mut Vec<PathBuf>,
+        loaded: &mut Loaded,
+    ) -> Result<(), IncludeError> {
+        let location = include.map(|position| loaded.sources.describe(position));
+
+        if stack.iter().any(|open| open == path) {
+            let chain: Vec<String> = stack
+                .iter()
+                .chain(Some(&path.to_path_buf()))
+                .map(|path| path.display().to_string())
+                .collect();
+            return Err(IncludeError {
+                message: format!("include cycle {}", chain.join(" -> ")),
+                location,
+            });
+        }
+
+        let source = match self.fs.read_to_string(path) {
+            Ok(source) => source,
+            Err(err) => {
+                return Err(IncludeError {
+                    message: format!("cannot read {}: {}", path.display(), err),
+                    location,
+                })
+            }
+        };
+
+        let start = loaded.sources.add(path, &source);
+        // borrow the text until a lexeme is kept, so each token is built once
+        let lexemes = match Lexer::new(&source).collect::<Result<Vec<Lexeme>, _>>() {
+            Ok(lexemes) => lexemes,
+            Err(err) => {
+                return Err(IncludeError {
+                    message: err.to_string(),
+                    location: Some(loaded.sources.describe(start + err.position())),
+                })
+            }
+        };
+
+        stack.push(path.to_path_buf());
+        loaded.tokens.reserve(lexemes.len());
+
+        let mut i = 0;
+        while i < lexemes.len() {
+            let position = lexemes[i].span.start;
+            let include = lexemes[i].kind == Kind::Word && lexemes[i].text.eq_ignore_ascii_case("include");
+            match (lexemes.get(i + 1), lexemes.get(i + 2).map(|end| end.kind)) {
+                (Some(name), end)
+                    if include
+                        && name.kind == Kind::Str
+                        && matches!(end, None | Some(Kind::Newline) | Some(Kind::Comment)) =>
+                {
+                    let name = String::from_utf8_lossy(&name.bytes()).into_owned();
+                    let resolved = self.resolve(path, &name, start + position, &loaded.sources)?;
+                    self.load_file(&resolved, Some(start + position), stack, loaded)?;
+                    i += 3;
+                    // a comment is followed by the end of the line
+                    if end == Some(Kind::Comment) {
+                        i += 1;
+                    }
+                }
+                _ if include => {
+                    return Err(IncludeError {
+                        message: "expected a quoted path after include".to_string(),
+                        location: Some(loaded.sources.describe(start + position)),
+                    });
+                }
+                _ => {
+                    loaded.tokens.push(lexemes[i].to_token().shifted(start));
+                    i += 1;
+                }
+            }
+
+            // only look for include at the start of a line
+            while i < lexemes.len() {
+                if lexemes[i - 1].kind == Kind::Newline {
+                    break;
+                }
+                loaded.tokens.push(lexemes[i].to_token().shifted(start));
+                i += 1;
+            }
+        }
+
+        // end the last line here, or it runs into the includer's next line
+        if let Some(last) = lexemes.last() {
+            if last.kind != Kind::Newline {
+                loaded.tokens.push(Token::EOL(start + source.len()));
+            }
+        }
+
+        stack.pop();
+        Ok(())
+    }
+
+    fn resolve(
+        &self,
+        from: &Path,
+        name: &str,
+        position: usize,
+        sources: &SourceMap,
+    ) -> Result<PathBuf, IncludeError> {
+        let base = from.parent().unwrap_or_else(|| Path::new(""));
+        let candidates = Some(base).into_iter().chain(self.include_paths.iter().map(PathBuf::as_path));
+
+        for dir in candidates {
+            let candidate = normalize(&dir.join(name));
+            if self.fs.exists(&candidate) {
+                return Ok(candidate);
+            }
+        }
+
+        Err(IncludeError {
+            message: format!("cannot find include file {}", name),
+            location: Some(sources.describe(position)),
+        })
+    }
+}
+
+// Resolves `.` and `..` without touching the file system, so the same file
+// reached through different relative paths is recognised in cycles.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match out.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    out.pop();
+                }
+                _ => out.push(".."),
+            },
+            other => out.push(other.as_os_str()),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::parse;
+
+    fn loader(files: &[(&str, &str)]) -> Loader<VirtualFileSystem> {
+        let mut fs = VirtualFileSystem::new();
+        for (path, source) in files {
+            fs.add(path, source);
+        }
+        Loader::new(fs).include_path("lib")
+    }
+
+    #[test]
+    fn nested_includes() {
+        let loader = loader(&[
+            ("src/main.s", "include \"defs.s\" ; constants\npush VALUE\nhalt\n"),
+            ("src/defs.s", "include \"../lib/more.s\"\nequ VALUE 2\n"),
+            ("lib/more.s", "push 1\n"),
+        ]);
+        let loaded = loader.load("src/main.s").unwrap();
+        assert_eq!(parse(&loaded.tokens).unwrap(), vec![0x03, 1, 0x03, 2, 0x00]);
+
+        let push = loaded.tokens.iter().find(|token| token.position() > 0).unwrap();
+        assert_eq!(loaded.sources.describe(push.position()), "lib/more.s:1:1");
+    }
+
+    #[test]
+    fn include_paths() {
+        let loader = loader(&[("main.s", "include \"std.s\"\n"), ("lib/std.s", "halt\n")]);
+        assert_eq!(parse(&loader.load("main.s").unwrap().tokens).unwrap(), vec![0x00]);
+    }
+
+    #[test]
+    fn missing_trailing_newline() {
+        let loader = loader(&[
+            ("main.s", "include \"a.s\"\npush 2\ninclude \"b.s\""),
+            ("a.s", "push 1"),
+            ("b.s", "halt"),
+        ]);
+        let loaded = loader.load("main.s").unwrap();
+        assert_eq!(parse(&loaded.tokens).unwrap(), vec![0x03, 1, 0x03, 2, 0x00]);
+    }
+
+    #[test]
+    fn errors() {
+        let loader = loader(&[
+            ("a.s", "include \"b.s\"\n"),
+            ("b.s", "\ninclude \"./a.s\"\n"),
+            ("c.s", "include \"missing.s\"\n"),
+            ("d.s", "include missing\n"),
+        ]);
+        let err = loader.load("a.s").unwrap_err();
+        assert_eq!(err.to_string(), "b.s:2:1: include cycle a.s -> b.s -> a.s");
+        let err = loader.load("c.s").unwrap_err();
+        assert_eq!(err.to_string(), "c.s:1:1: cannot find include file missing.s");
+        let err = loader.load("d.s").unwrap_err();
+        assert_eq!(err.to_string(), "d.s:1:1: expected a quoted path after include");
+        assert!(loader.load("e.s").unwrap_err().to_string().starts_with("cannot read e.s"));
+    }
+}
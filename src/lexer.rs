@@ -0,0 +1,330 @@
+//! Single pass lexer over the bytes of a source. Lexemes borrow their text
+//! from the source and are only turned into owned `Token`s on request.
+
+use assembler::{Token, TokenizerError};
+use ast::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Word,
+    /// A decimal, `0x` hex or quoted character literal and its value.
+    Number(u16),
+    /// A quoted string; see `Lexeme::bytes`.
+    Str,
+    Operator,
+    Colon,
+    Comma,
+    /// From a `;` up to the end of the line, without trailing whitespace.
+    Comment,
+    /// The end of a line with other lexemes on it. Blank lines have none.
+    /// A last line without a `\n` still gets one, with empty text.
+    Newline,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lexeme<'a> {
+    pub kind: Kind,
+    /// The source text of the lexeme.
+    pub text: &'a str,
+    /// Byte offsets of the text in the source.
+    pub span: Span,
+}
+
+impl<'a> Lexeme<'a> {
+    /// The bytes of a string literal with escapes replaced.
+    pub fn bytes(&self) -> Vec<u8> {
+        let inner = &self.text.as_bytes()[1..self.text.len() - 1];
+        let mut bytes = Vec::with_capacity(inner.len());
+        let mut i = 0;
+        while i < inner.len() {
+            if inner[i] != b'\\' {
+                bytes.push(inner[i]);
+                i += 1;
+                continue;
+            }
+            let (byte, len) = escape(inner, i + 1).unwrap_or((0, 1));
+            bytes.push(byte);
+            i += 1 + len;
+        }
+        bytes
+    }
+
+    pub fn to_token(&self) -> Token {
+        let start = self.span.start;
+        match self.kind {
+            Kind::Word => Token::Word(start, self.text.to_string()),
+            Kind::Number(value) => Token::Number(start, value),
+            Kind::Str => Token::Str(start, self.bytes()),
+            Kind::Operator => Token::Operator(start, self.text.to_string()),
+            Kind::Colon => Token::Colon(start),
+            Kind::Comma => Token::Comma(start),
+            Kind::Comment => Token::Comment(start, self.text[1..].to_string()),
+            Kind::Newline => Token::EOL(start),
+        }
+    }
+}
+
+/// Iterates over the lexemes of a source. Any run of spaces, tabs and `\r`
+/// separates lexemes. Iteration stops after the first error.
+#[derive(Debug, Clone)]
+pub struct Lexer<'a> {
+    source: &'a str,
+    position: usize,
+    blank: bool,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(source: &'a str) -> Lexer<'a> {
+        Lexer {
+            source,
+            position: 0,
+            blank: true,
+        }
+    }
+
+    fn lexeme(&mut self, kind: Kind, start: usize, end: usize) -> Lexeme<'a> {
+        self.position = end;
+        Lexeme {
+            kind,
+            text: &self.source[start..end],
+            span: Span { start, end },
+        }
+    }
+
+    fn error(&mut self, position: usize, pattern: String) -> TokenizerError {
+        self.position = self.source.len();
+        self.blank = true;
+        TokenizerError { pattern, position }
+    }
+
+    // End of the run of bytes from `start` matching `accept`.
+    fn run_end(&self, start: usize, accept: fn(u8) -> bool) -> usize {
+        let bytes = &self.source.as_bytes()[start..];
+        start + bytes.iter().position(|byte| !accept(*byte)).unwrap_or(bytes.len())
+    }
+
+    fn number(&mut self, start: usize) -> Result<Lexeme<'a>, TokenizerError> {
+        let bytes = self.source.as_bytes();
+        let (end, parsed) = if bytes[start] == b'0' && bytes.get(start + 1) == Some(&b'x') {
+            let end = self.run_end(start + 2, |byte| byte.is_ascii_alphanumeric());
+            (end, u16::from_str_radix(&self.source[start + 2..end], 16))
+        } else {
+            let end = self.run_end(start, is_word);
+            let text = &self.source[start..end];
+            if !text.bytes().all(|byte| byte.is_ascii_digit()) {
+                // numeric label references like `1f`
+                return Ok(self.lexeme(Kind::Word, start, end));
+            }
+            (end, text.parse())
+        };
+
+        match parsed {
+            Ok(value) => Ok(self.lexeme(Kind::Number(value), start, end)),
+            Err(_) => {
+                let pattern = self.source[start..end].to_string();
+                Err(self.error(start, pattern))
+            }
+        }
+    }
+
+    // A quoted string or character literal; returns the offset after the
+    // closing quote and the number of bytes it stands for.
+    fn quoted(&mut self, start: usize) -> Result<(usize, usize), TokenizerError> {
+        let bytes = self.source.as_bytes();
+        let quote = bytes[start];
+        let mut len = 0;
+        let mut i = start + 1;
+
+        loop {
+            match bytes.get(i) {
+                None => return Err(self.error(i, "end of input".to_string())),
+                Some(b'\n') => return Err(self.error(i, "\\n".to_string())),
+                Some(b'\\') => match escape(bytes, i + 1) {
+                    Some((_, escaped)) => i += 1 + escaped,
+                    None => {
+                        let end = self.source[i + 1..]
+                            .char_indices()
+                            .nth(if bytes.get(i + 1) == Some(&b'x') { 3 } else { 1 })
+                            .map_or(self.source.len(), |(offset, _)| i + 1 + offset);
+                        let pattern = self.source[i..end].to_string();
+                        return Err(self.error(i, pattern));
+                    }
+                },
+                Some(byte) if *byte == quote => return Ok((i + 1, len)),
+                Some(_) => i += 1,
+            }
+            // bytes of multi-byte characters are counted one by one
+            len += 1;
+        }
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<Lexeme<'a>, TokenizerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let bytes = self.source.as_bytes();
+        let (start, byte) = loop {
+            let start = self.run_end(self.position, |byte| matches!(byte, b' ' | b'\t' | b'\r'));
+            let byte = match bytes.get(start) {
+                Some(byte) => *byte,
+                // end the last line even if the source does not
+                None if !self.blank => {
+                    self.blank = true;
+                    return Some(Ok(self.lexeme(Kind::Newline, start, start)));
+                }
+                None => return None,
+            };
+            if byte == b'\n' && self.blank {
+                self.position = start + 1;
+                continue;
+            }
+            break (start, byte);
+        };
+
+        if byte == b'\n' {
+            self.blank = true;
+            return Some(Ok(self.lexeme(Kind::Newline, start, start + 1)));
+        }
+        self.blank = false;
+
+        let lexeme = match byte {
+            b';' => {
+                let end = self.run_end(start, |byte| byte != b'\n');
+                let len = self.source[start..end].trim_end().len();
+                Ok(self.lexeme(Kind::Comment, start, start + len))
+            }
+            b'"' => self.quoted(start).map(|(end, _)| self.lexeme(Kind::Str, start, end)),
+            b'\'' => match self.quoted(start) {
+                Ok((end, 1)) => {
+                    let value = match bytes[start + 1] {
+                        b'\\' => escape(bytes, start + 2).map_or(0, |(byte, _)| byte),
+                        byte => byte,
+                    };
+                    Ok(self.lexeme(Kind::Number(value as u16), start, end))
+                }
+                Ok((end, _)) => {
+                    let pattern = self.source[start..end].to_string();
+                    Err(self.error(start, pattern))
+                }
+                Err(err) => Err(err),
+            },
+            b':' => Ok(self.lexeme(Kind::Colon, start, start + 1)),
+            b',' => Ok(self.lexeme(Kind::Comma, start, start + 1)),
+            b'<' | b'>' => {
+                let next = bytes.get(start + 1);
+                let len = if next == Some(&byte) || next == Some(&b'=') { 2 } else { 1 };
+                Ok(self.lexeme(Kind::Operator, start, start + len))
+            }
+            b'=' | b'!' => match bytes.get(start + 1) {
+                Some(b'=') => Ok(self.lexeme(Kind::Operator, start, start + 2)),
+                _ => Err(self.error(start, (byte as char).to_string())),
+            },
+            b'+' | b'-' | b'*' | b'/' | b'%' | b'&' | b'|' | b'^' | b'~' | b'(' | b')' => {
+                Ok(self.lexeme(Kind::Operator, start, start + 1))
+            }
+            b'0'..=b'9' => self.number(start),
+            byte if is_word(byte) => {
+                let end = self.run_end(start, is_word);
+                Ok(self.lexeme(Kind::Word, start, end))
+            }
+            _ => {
+                let pattern = self.source[start..].chars().next().unwrap_or(' ').to_string();
+                Err(self.error(start, pattern))
+            }
+        };
+        Some(lexeme)
+    }
+}
+
+fn is_word(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'.'
+}
+
+// The byte an escape sequence starting after the backslash at `i - 1`
+// stands for, and the length of the sequence after the backslash.
+fn escape(bytes: &[u8], i: usize) -> Option<(u8, usize)> {
+    let byte = match *bytes.get(i)? {
+        b'n' => b'\n',
+        b'r' => b'\r',
+        b't' => b'\t',
+        b'0' => 0,
+        b'\\' => b'\\',
+        b'"' => b'"',
+        b'\'' => b'\'',
+        b'x' => {
+            let digits = bytes.get(i + 1..i + 3)?;
+            let digits = std::str::from_utf8(digits).ok()?;
+            return u8::from_str_radix(digits, 16).ok().map(|byte| (byte, 3));
+        }
+        _ => return None,
+    };
+    Some((byte, 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::tokenize;
+    use ast;
+
+    fn kinds(source: &str) -> Vec<Kind> {
+        Lexer::new(source).map(|lexeme| lexeme.unwrap().kind).collect()
+    }
+
+    fn error(source: &str) -> (usize, String) {
+        let err = tokenize(source).unwrap_err();
+        (err.position(), err.pattern)
+    }
+
+    #[test]
+    fn lexemes_and_spans() {
+        let source = "\r\n\tloop: push 'a' + 0x1F ; note  \r\n\n  db \"a\\x41\\n\", 1f\n";
+        let lexemes: Vec<Lexeme> = Lexer::new(source).map(Result::unwrap).collect();
+        let texts: Vec<&str> = lexemes.iter().map(|lexeme| lexeme.text).collect();
+        assert_eq!(
+            texts,
+            vec![
+                "loop", ":", "push", "'a'", "+", "0x1F", "; note", "\n", "db", "\"a\\x41\\n\"", ",",
+                "1f", "\n"
+            ]
+        );
+        assert_eq!(lexemes[3].kind, Kind::Number(97));
+        assert_eq!(lexemes[5].kind, Kind::Number(0x1f));
+        assert_eq!(lexemes[11].kind, Kind::Word);
+        assert_eq!(lexemes[9].bytes(), b"aA\n".to_vec());
+        assert_eq!(lexemes[0].span, Span { start: 3, end: 7 });
+        assert_eq!(&source[lexemes[6].span.start..lexemes[6].span.end], "; note");
+    }
+
+    #[test]
+    fn ends_the_last_line() {
+        assert_eq!(kinds("halt"), vec![Kind::Word, Kind::Newline]);
+        assert_eq!(kinds("halt\n"), vec![Kind::Word, Kind::Newline]);
+        assert_eq!(kinds("halt\n  \n"), vec![Kind::Word, Kind::Newline]);
+        assert_eq!(kinds(""), vec![]);
+        assert_eq!(kinds("\n\t\n"), vec![]);
+
+        let tokens = tokenize("push 1\nhalt").unwrap();
+        assert_eq!(tokens.last(), Some(&Token::EOL(11)));
+        assert_eq!(ast::parse(&tokens).unwrap().items.len(), 2);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("push 70000\n"), (5, "70000".to_string()));
+        assert_eq!(error("push 0xfffff\n"), (5, "0xfffff".to_string()));
+        assert_eq!(error("db \"abc\n"), (7, "\\n".to_string()));
+        assert_eq!(error("db \"abc"), (7, "end of input".to_string()));
+        assert_eq!(error("db \"\\q\"\n"), (4, "\\q".to_string()));
+        assert_eq!(error("push 'ab'\n"), (5, "'ab'".to_string()));
+        assert_eq!(error("push 1 = 2\n"), (7, "=".to_string()));
+        assert_eq!(error("push $\n"), (5, "$".to_string()));
+
+        // nothing follows an error, not even the end of the line
+        let mut lexer = Lexer::new("push $");
+        assert!(lexer.next().unwrap().is_ok());
+        assert!(lexer.next().unwrap().is_err());
+        assert!(lexer.next().is_none());
+    }
+}
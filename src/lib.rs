@@ -1,26 +1,178 @@
 pub mod assembler;
+pub mod ast;
+pub mod builder;
+pub mod debug;
+pub mod device;
+pub mod disasm;
+pub mod expr;
+pub mod format;
+pub mod formatter;
+pub mod history;
+pub mod image;
+pub mod include;
+pub mod lexer;
+pub mod link;
+pub mod lint;
+pub mod listing;
+pub mod macros;
+pub mod object;
+mod reader;
+pub mod snapshot;
+pub mod source;
+pub mod stack;
+pub mod trace;
 
-const MEMORY_SIZE: usize = 256;
-const PC: usize = MEMORY_SIZE - 1;
-const SP: usize = MEMORY_SIZE - 2;
+use std::fmt;
+use std::mem;
+
+use builder::{Config, EmulatorBuilder};
+use device::Mapping;
+use history::{History, UndoStep};
+use image::{Image, ImageError, Profile};
+use snapshot::{Snapshot, SnapshotError};
+use trace::{MemoryWrite, Trace, TraceStep};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    IllegalInstruction { address: u8, opcode: u8 },
+    StackOverflow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    /// The segment runs past the end of memory.
+    OutOfMemory { address: usize, len: usize },
+    /// The segment would overwrite the registers or the stack.
+    Reserved { address: usize },
+    /// Two segments write to the same address.
+    Overlap { address: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::OutOfMemory { address, len } => write!(
+                f,
+                "{} bytes at {:02X} do not fit in memory",
+                len, address
+            ),
+            LoadError::Reserved { address } => {
+                write!(f, "cannot load over the stack or registers at {:02X}", address)
+            }
+            LoadError::Overlap { address } => write!(f, "segments overlap at {:02X}", address),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Halted,
+    Timeout,
+    Fault(Fault),
+}
+
+/// Number of cycles an opcode takes to execute.
+pub fn cycle_cost(opcode: u8) -> u64 {
+    match opcode {
+        0x00 => 1,
+        0x01 => 2,
+        0x02 => 3,
+        0x03 => 2,
+        0x04 => 1,
+        0x05..=0x09 => 1,
+        0x0A => 2,
+        _ => 1,
+    }
+}
 
 pub struct Emulator {
-    pub memory: [u8; MEMORY_SIZE],
+    pub memory: Vec<u8>,
     pub running: bool,
+    pub fault: Option<Fault>,
+    pub cycles: u64,
+    pub steps: u64,
+    pub max_steps: Option<u64>,
+    pub trace: Option<Trace>,
+    pub history: Option<History>,
+    writes: Vec<MemoryWrite>,
+    config: Config,
+    devices: Vec<Mapping>,
+}
+
+impl Default for Emulator {
+    fn default() -> Emulator {
+        Emulator::new()
+    }
 }
 
 impl Emulator {
     pub fn new() -> Emulator {
+        Emulator::with_config(Config::default(), Vec::new())
+    }
+
+    pub fn builder() -> EmulatorBuilder {
+        EmulatorBuilder::new()
+    }
+
+    fn with_config(config: Config, devices: Vec<Mapping>) -> Emulator {
+        let mut memory = vec![0; config.memory_size];
+        memory[config.pc()] = config.entry_point;
+        memory[config.sp()] = config.stack_base;
         Emulator {
-            memory: [0; MEMORY_SIZE],
-            running: true,
+            memory,
+            running: config.running,
+            fault: None,
+            cycles: 0,
+            steps: 0,
+            max_steps: None,
+            trace: None,
+            history: None,
+            writes: Vec::new(),
+            config,
+            devices,
         }
     }
 
-    pub fn run(&mut self) {
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn run(&mut self) -> Outcome {
+        self.run_until(None)
+    }
+
+    /// Runs until the machine stops or at least `cycles` more cycles have
+    /// been spent, whichever comes first.
+    pub fn run_for(&mut self, cycles: u64) -> Outcome {
+        let budget = self.cycles.saturating_add(cycles);
+        self.run_until(Some(budget))
+    }
+
+    fn run_until(&mut self, cycle_budget: Option<u64>) -> Outcome {
         while self.running {
+            if self.out_of_budget(cycle_budget) {
+                return Outcome::Timeout;
+            }
             self.next();
         }
+
+        match self.fault {
+            Some(fault) => Outcome::Fault(fault),
+            None => Outcome::Halted,
+        }
+    }
+
+    fn out_of_budget(&self, cycle_budget: Option<u64>) -> bool {
+        let steps_exceeded = match self.max_steps {
+            Some(max_steps) => self.steps >= max_steps,
+            None => false,
+        };
+        let cycles_exceeded = match cycle_budget {
+            Some(budget) => self.cycles >= budget,
+            None => false,
+        };
+
+        steps_exceeded || cycles_exceeded
     }
 
     pub fn early_halt(&mut self) {
@@ -28,45 +180,306 @@ impl Emulator {
     }
 
     pub fn reset(&mut self) {
-        self.write(PC, 0);
-        self.write(SP, (MEMORY_SIZE - 3) as u8);
+        let (pc, sp) = (self.config.pc(), self.config.sp());
+        let (entry_point, stack_base) = (self.config.entry_point, self.config.stack_base);
+        self.write(pc, entry_point);
+        self.write(sp, stack_base);
         self.running = true;
+        self.fault = None;
+        self.cycles = 0;
+        self.steps = 0;
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
     }
 
-    pub fn load(&mut self, program: &[u8]) {
-        for i in 0..program.len() {
-            self.write(i, program[i]);
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            memory: self.memory.to_vec(),
+            running: self.running,
+            fault: self.fault,
+            cycles: self.cycles,
+            steps: self.steps,
         }
     }
 
+    /// Replaces the machine state with `snapshot`. Recorded history is
+    /// discarded since it no longer applies to the restored state.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+        if snapshot.memory.len() != self.memory.len() {
+            return Err(SnapshotError::SizeMismatch {
+                expected: self.memory.len(),
+                found: snapshot.memory.len(),
+            });
+        }
+
+        self.memory.copy_from_slice(&snapshot.memory);
+        self.running = snapshot.running;
+        self.fault = snapshot.fault;
+        self.cycles = snapshot.cycles;
+        self.steps = snapshot.steps;
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+
+        Ok(())
+    }
+
+    /// Loads `program` at the configured load address.
+    pub fn load(&mut self, program: &[u8]) -> Result<(), LoadError> {
+        let address = self.config.load_address;
+        self.load_at(address, program)
+    }
+
+    pub fn load_at(&mut self, address: u8, bytes: &[u8]) -> Result<(), LoadError> {
+        self.load_segments(&[(address, bytes)])
+    }
+
+    /// Loads several `(address, bytes)` segments. Nothing is written unless
+    /// all of them fit in memory below the stack and do not overlap.
+    pub fn load_segments(&mut self, segments: &[(u8, &[u8])]) -> Result<(), LoadError> {
+        let reserved = self.reserved_start();
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for (address, bytes) in segments {
+            let start = *address as usize;
+            let end = start + bytes.len();
+            if end > self.config.memory_size {
+                return Err(LoadError::OutOfMemory {
+                    address: start,
+                    len: bytes.len(),
+                });
+            }
+            if end > reserved {
+                return Err(LoadError::Reserved {
+                    address: start.max(reserved),
+                });
+            }
+            let overlap = ranges
+                .iter()
+                .find(|(other_start, other_end)| start < *other_end && *other_start < end);
+            if let Some((other_start, _)) = overlap {
+                return Err(LoadError::Overlap {
+                    address: start.max(*other_start),
+                });
+            }
+            ranges.push((start, end));
+        }
+
+        for (address, bytes) in segments {
+            for (i, byte) in bytes.iter().enumerate() {
+                self.write(*address as usize + i, *byte);
+            }
+        }
+        Ok(())
+    }
+
+    // The first address programs cannot be loaded at: the bottom of the
+    // stack when it is limited, otherwise the stack base.
+    fn reserved_start(&self) -> usize {
+        if self.config.stack_limit > 0 {
+            self.config.stack_limit as usize
+        } else {
+            self.config.stack_base as usize
+        }
+    }
+
+    /// Loads an image built for a compatible machine. The image's load
+    /// address and entry point replace the configured ones, so `reset`
+    /// starts the program.
+    pub fn load_image(&mut self, image: &Image) -> Result<(), ImageError> {
+        if !image.profile.runs_on(&self.config) {
+            return Err(ImageError::Incompatible {
+                image: image.profile.clone(),
+                machine: Profile {
+                    memory_size: self.config.memory_size,
+                },
+            });
+        }
+        if image.entry_point as usize >= self.config.sp() {
+            return Err(ImageError::Invalid("entry point is outside of memory"));
+        }
+
+        self.load_at(image.load_address, &image.code)?;
+        self.config.load_address = image.load_address;
+        self.config.entry_point = image.entry_point;
+        Ok(())
+    }
+
+    /// Reads a byte, wrapping addresses past the end of memory around.
     pub fn read(&self, pos: usize) -> u8 {
-        self.memory[pos]
+        let pos = pos % self.memory.len();
+        match self.devices.iter().find(|mapping| mapping.contains(pos)) {
+            Some(mapping) => mapping.device.read(pos - mapping.base),
+            None => self.memory[pos],
+        }
     }
 
+    /// Writes a byte, wrapping addresses past the end of memory around.
+    /// Writes to a device are also kept in the backing memory.
     pub fn write(&mut self, pos: usize, data: u8) {
+        let pos = pos % self.memory.len();
+        if let Some(mapping) = self.devices.iter_mut().find(|mapping| mapping.contains(pos)) {
+            mapping.device.write(pos - mapping.base, data);
+        }
+        if self.trace.is_some() || self.history.is_some() {
+            self.writes.push(MemoryWrite {
+                address: pos as u8,
+                old: self.memory[pos],
+                new: data,
+            });
+        }
         self.memory[pos] = data;
     }
 
+    /// Current stack contents, top of the stack first.
+    pub fn stack(&self) -> Vec<u8> {
+        let top = self.read(self.config.sp()) as usize + 1;
+        let base = self.config.stack_base as usize;
+        (top..=base).map(|pos| self.read(pos)).collect()
+    }
+
+    // Pushes onto the stack, faulting if it would grow past the limit.
     fn push(&mut self, data: u8) {
-        let pos = self.read(SP);
+        let sp = self.config.sp();
+        let pos = self.read(sp);
+        if pos < self.config.stack_limit {
+            self.running = false;
+            self.fault = Some(Fault::StackOverflow);
+            return;
+        }
         self.write(pos as usize, data);
-        self.write(SP, pos - 1);
+        self.write(sp, pos.wrapping_sub(1));
     }
 
     fn pop(&mut self) -> u8 {
-        self.memory[SP] += 1;
-        let data = self.read(self.memory[SP] as usize);
-        data
+        let sp = self.config.sp();
+        let pos = self.read(sp).wrapping_add(1);
+        self.write(sp, pos);
+        self.read(pos as usize)
+    }
+
+    fn advance_pc(&mut self) {
+        let pc = self.config.pc();
+        let next = (self.read(pc) as usize + 1) % self.memory.len();
+        self.write(pc, next as u8);
     }
 
+    /// Executes one instruction, unless the machine has stopped or
+    /// `max_steps` is used up. Cycle budgets only apply within `run_for`.
     pub fn step(&mut self) {
-        if self.running {
+        if self.running && !self.out_of_budget(None) {
             self.next()
         }
     }
 
+    /// Undoes the most recently executed instruction. Returns `false` if
+    /// there is no recorded history left to undo.
+    pub fn step_back(&mut self) -> bool {
+        let step = match self.history.as_mut().and_then(|history| history.pop()) {
+            Some(step) => step,
+            None => return false,
+        };
+
+        for write in step.writes.iter().rev() {
+            self.memory[write.address as usize] = write.old;
+        }
+        self.cycles = step.cycles;
+        self.steps = step.steps;
+        self.running = true;
+        self.fault = None;
+
+        true
+    }
+
+    /// Steps back until just before the last instruction that wrote to
+    /// `address`. Returns `false`, leaving the machine as it is, if no such
+    /// write is in the history.
+    pub fn reverse_continue(&mut self, address: usize) -> bool {
+        let newer = self
+            .history
+            .as_ref()
+            .and_then(|history| history.iter().rev().position(|step| step.writes_to(address)));
+        match newer {
+            Some(newer) => {
+                for _ in 0..=newer {
+                    self.step_back();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Steps back until the cycle counter is at or before `cycle`. Returns
+    /// `false`, leaving the machine as it is, if the history does not reach
+    /// back that far.
+    pub fn rewind_to_cycle(&mut self, cycle: u64) -> bool {
+        if self.cycles <= cycle {
+            return true;
+        }
+        let reachable = match self.history.as_ref().and_then(|history| history.first()) {
+            Some(oldest) => oldest.cycles <= cycle,
+            None => false,
+        };
+        if !reachable {
+            return false;
+        }
+        while self.cycles > cycle {
+            self.step_back();
+        }
+        true
+    }
+
     fn next(&mut self) {
-        let instruction = self.read(self.memory[PC] as usize);
+        if self.trace.is_none() && self.history.is_none() {
+            self.execute();
+            return;
+        }
+
+        let pc = self.read(self.config.pc());
+        let opcode = self.read(pc as usize);
+        let operand = if disasm::instruction_length(opcode) > 1 {
+            Some(self.read(pc as usize + 1))
+        } else {
+            None
+        };
+        let cycle = self.cycles;
+        let steps = self.steps;
+        let stack_before = self.stack();
+
+        self.writes.clear();
+        self.execute();
+        let writes = mem::take(&mut self.writes);
+
+        if let Some(history) = self.history.as_mut() {
+            history.record(UndoStep {
+                cycles: cycle,
+                steps,
+                writes: writes.clone(),
+            });
+        }
+
+        let stack_after = self.stack();
+        if let Some(trace) = self.trace.as_mut() {
+            trace.record(TraceStep {
+                cycle,
+                pc,
+                opcode,
+                operand,
+                stack_before,
+                stack_after,
+                writes,
+            });
+        }
+    }
+
+    fn execute(&mut self) {
+        let pc = self.config.pc();
+        let address = self.read(pc);
+        let instruction = self.read(address as usize);
+
+        self.cycles += cycle_cost(instruction);
+        self.steps += 1;
 
         match instruction {
             0x00 => {
@@ -83,8 +496,8 @@ impl Emulator {
                 self.write(location as usize, data);
             }
             0x03 => {
-                self.memory[PC] += 1;
-                let location = self.memory[PC];
+                self.advance_pc();
+                let location = self.read(pc);
                 let data = self.read(location as usize);
                 self.push(data);
             }
@@ -94,12 +507,12 @@ impl Emulator {
             0x05 => {
                 let a = self.pop();
                 let b = self.pop();
-                self.push((a + b) as u8);
+                self.push(a.wrapping_add(b));
             }
             0x06 => {
                 let a = self.pop();
                 let b = self.pop();
-                self.push((a - b) as u8);
+                self.push(a.wrapping_sub(b));
             }
             0x07 => {
                 let a = self.pop();
@@ -117,15 +530,15 @@ impl Emulator {
                 self.push(a ^ b);
             }
             0x0A => {
-                self.memory[PC] += 1;
+                self.advance_pc();
 
                 let location = self.pop();
-                let cond_location = self.read(PC);
+                let cond_location = self.read(pc);
                 let condition = self.read(cond_location as usize);
 
                 let should_jump = match condition {
                     0x00 => true,
-                    0x01...0x06 => {
+                    0x01..=0x06 => {
                         let data = self.pop() as i8;
                         match condition {
                             0x01 => data > 0,
@@ -141,12 +554,170 @@ impl Emulator {
                 };
 
                 if should_jump {
-                    self.write(PC, location);
+                    self.write(pc, location);
                 }
             }
-            _ => {}
+            opcode => {
+                self.running = false;
+                self.fault = Some(Fault::IllegalInstruction { address, opcode });
+                return;
+            }
+        }
+
+        if self.fault.is_some() {
+            // Leave the program counter on the faulting instruction.
+            self.write(pc, address);
+            return;
         }
 
-        self.memory[PC] += 1;
+        self.advance_pc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler;
+    use history::History;
+
+    // An emulator with `program` loaded and reset.
+    fn emulator(program: &[u8]) -> Emulator {
+        let mut emu = Emulator::new();
+        emu.load(program).unwrap();
+        emu.reset();
+        emu
+    }
+
+    #[test]
+    fn run_halts() {
+        let mut emu = emulator(&[0x03, 0x05, 0x04, 0x00]);
+        assert_eq!(emu.run(), Outcome::Halted);
+        assert_eq!(emu.steps, 3);
+        assert_eq!(emu.cycles, 2 + 1 + 1);
+    }
+
+    #[test]
+    fn pseudo_instructions_run() {
+        let source = "mov b, a\ninc a\npush 0x0F\nnot\npush c\nstore\n\
+                      loop: dec b\ninc n\npush b\nload\njz done\njmp loop\ndone: halt\n\
+                      a: db 5\nb: db 0\nc: db 0\nn: db 0\n";
+        let program = assembler::parse(&assembler::tokenize(source).unwrap()).unwrap();
+        let mut emu = emulator(&program);
+        assert_eq!(emu.run(), Outcome::Halted);
+
+        // a, b, c and n follow the code
+        let data = program.len() - 4;
+        assert_eq!(emu.memory[data..data + 4], [6, 0, 0xF0, 5]);
+    }
+
+    #[test]
+    fn illegal_opcode_faults() {
+        let mut emu = emulator(&[0x04, 0xEE]);
+        let fault = Fault::IllegalInstruction { address: 1, opcode: 0xEE };
+        assert_eq!(emu.run(), Outcome::Fault(fault));
+        assert_eq!(emu.memory[emu.config().pc()], 1);
+    }
+
+    #[test]
+    fn load_rejects_bad_segments_without_writing() {
+        let mut emu = EmulatorBuilder::new().memory_size(32).build().unwrap();
+        let before = emu.snapshot();
+        let data: &[u8] = &[1, 2, 3];
+
+        assert_eq!(
+            emu.load_at(30, data),
+            Err(LoadError::OutOfMemory { address: 30, len: 3 })
+        );
+        // the stack starts at 29, right below the registers
+        assert_eq!(emu.load_at(27, data), Err(LoadError::Reserved { address: 29 }));
+        assert_eq!(
+            emu.load_segments(&[(0, data), (2, &[4])]),
+            Err(LoadError::Overlap { address: 2 })
+        );
+        // the first segment fits, but nothing is written when the second does not
+        assert_eq!(
+            emu.load_segments(&[(0, data), (28, data)]),
+            Err(LoadError::Reserved { address: 29 })
+        );
+        assert_eq!(emu.snapshot(), before);
+
+        emu.load_segments(&[(0, data), (3, &[4])]).unwrap();
+        assert_eq!(emu.memory[..4], [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn load_stops_at_stack_limit() {
+        let mut emu = EmulatorBuilder::new().stack_base(0x80).stack_limit(0x70).build().unwrap();
+        assert_eq!(emu.load_at(0x6E, &[1, 2, 3]), Err(LoadError::Reserved { address: 0x70 }));
+        assert_eq!(emu.load_at(0x72, &[1]), Err(LoadError::Reserved { address: 0x72 }));
+        assert!(emu.memory[0x6E..0x73].iter().all(|byte| *byte == 0));
+        emu.load_at(0x6E, &[1, 2]).unwrap();
+    }
+
+    #[test]
+    fn budgets_stop_endless_loops() {
+        // push 0xFF; jp, which continues at address 0
+        let program = [0x03, 0xFF, 0x0A, 0x00];
+        let mut emu = emulator(&program);
+        assert_eq!(emu.run_for(10), Outcome::Timeout);
+        assert!(emu.cycles >= 10);
+
+        let mut emu = emulator(&program);
+        emu.max_steps = Some(5);
+        assert_eq!(emu.run(), Outcome::Timeout);
+        assert_eq!(emu.steps, 5);
+    }
+
+    // push 5; push 0x40; store; push 6; pop; halt
+    const STORE: [u8; 9] = [0x03, 0x05, 0x03, 0x40, 0x02, 0x03, 0x06, 0x04, 0x00];
+
+    #[test]
+    fn reverse_continue_stops_before_last_write() {
+        let mut emu = emulator(&STORE);
+        emu.history = Some(History::new());
+        emu.run();
+        assert_eq!(emu.memory[0x40], 5);
+
+        assert!(emu.reverse_continue(0x40));
+        assert_eq!(emu.memory[0x40], 0);
+        assert_eq!(emu.memory[emu.config().pc()], 4);
+        assert_eq!(emu.steps, 2);
+    }
+
+    #[test]
+    fn reverse_continue_without_write_keeps_state() {
+        let mut emu = emulator(&STORE);
+        emu.history = Some(History::new());
+        emu.run();
+        let before = emu.snapshot();
+
+        assert!(!emu.reverse_continue(0x41));
+        assert_eq!(emu.snapshot(), before);
+    }
+
+    #[test]
+    fn rewind_to_cycle() {
+        let mut emu = emulator(&STORE);
+        emu.history = Some(History::with_limit(2));
+        emu.run();
+        let before = emu.snapshot();
+
+        // only the last two steps are kept
+        assert!(!emu.rewind_to_cycle(0));
+        assert_eq!(emu.snapshot(), before);
+
+        assert!(emu.rewind_to_cycle(emu.cycles - 1));
+        assert_eq!(emu.steps, 5);
+        assert!(emu.running);
+    }
+
+    #[test]
+    fn step_respects_max_steps() {
+        let mut emu = emulator(&[0x04, 0x04, 0x00]);
+        emu.max_steps = Some(1);
+        emu.step();
+        emu.step();
+        assert_eq!(emu.steps, 1);
+        assert_eq!(emu.memory[emu.config().pc()], 1);
     }
 }
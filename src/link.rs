@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Write;
+
+use object::{Library, Object, Target};
+
+const MEMORY_SIZE: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct LinkError {
+    message: String,
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+fn error<T>(message: String) -> Result<T, LinkError> {
+    Err(LinkError { message })
+}
+
+fn section_base(
+    bases: &HashMap<(usize, usize), usize>,
+    index: usize,
+    section: usize,
+    object: &Object,
+) -> Result<usize, LinkError> {
+    match bases.get(&(index, section)) {
+        Some(base) => Ok(*base),
+        None => error(format!("unknown section {} in {}", section, object.name)),
+    }
+}
+
+/// Where a section of an object ended up in the image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placement {
+    pub section: String,
+    pub object: String,
+    pub address: usize,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapSymbol {
+    pub name: String,
+    pub object: String,
+    pub value: i64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkMap {
+    pub placements: Vec<Placement>,
+    /// Exported symbols sorted by value.
+    pub symbols: Vec<MapSymbol>,
+}
+
+impl LinkMap {
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        out.push_str("Sections:\n");
+        for placement in &self.placements {
+            writeln!(
+                out,
+                "{:02X} {:02X} {:<12} {}",
+                placement.address, placement.len, placement.section, placement.object
+            )
+            .unwrap();
+        }
+        out.push_str("\nSymbols:\n");
+        for symbol in &self.symbols {
+            writeln!(out, "{:02X} {:<16} {}", symbol.value, symbol.name, symbol.object).unwrap();
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Linked {
+    pub image: Vec<u8>,
+    pub map: LinkMap,
+}
+
+/// Combines objects into an image starting at address 0.
+///
+/// Sections with the same name are placed next to each other, in the order
+/// the names first appear. Objects from libraries are only linked in when
+/// they export a symbol that is imported but not yet defined.
+#[derive(Debug, Clone, Default)]
+pub struct Linker {
+    objects: Vec<Object>,
+    libraries: Vec<Library>,
+}
+
+impl Linker {
+    pub fn new() -> Linker {
+        Linker::default()
+    }
+
+    pub fn object(mut self, object: Object) -> Linker {
+        self.objects.push(object);
+        self
+    }
+
+    pub fn library(mut self, library: Library) -> Linker {
+        self.libraries.push(library);
+        self
+    }
+
+    pub fn link(&self) -> Result<Linked, LinkError> {
+        let objects = self.select()?;
+
+        // lay out sections by name
+        let mut names: Vec<&str> = Vec::new();
+        for object in &objects {
+            for section in &object.sections {
+                if !names.contains(&section.name.as_ref()) {
+                    names.push(&section.name);
+                }
+            }
+        }
+
+        let mut bases = HashMap::new();
+        let mut placements = Vec::new();
+        let mut address: usize = 0;
+        for name in names {
+            for (index, object) in objects.iter().enumerate() {
+                for (number, section) in object.sections.iter().enumerate() {
+                    if section.name != name {
+                        continue;
+                    }
+                    let align = section.align.max(1);
+                    address = address.div_ceil(align) * align;
+                    bases.insert((index, number), address);
+                    if !section.data.is_empty() {
+                        placements.push(Placement {
+                            section: section.name.clone(),
+                            object: object.name.clone(),
+                            address,
+                            len: section.data.len(),
+                        });
+                    }
+                    address += section.data.len();
+                }
+            }
+        }
+        if address > MEMORY_SIZE {
+            return error(format!("image of {} bytes does not fit in memory", address));
+        }
+
+        let mut symbols = HashMap::new();
+        let mut map_symbols = Vec::new();
+        for (index, object) in objects.iter().enumerate() {
+            for export in &object.exports {
+                let value = match export.section {
+                    Some(section) => section_base(&bases, index, section, object)? as i64 + export.value,
+                    None => export.value,
+                };
+                symbols.insert(export.name.as_ref(), value);
+                map_symbols.push(MapSymbol {
+                    name: export.name.clone(),
+                    object: object.name.clone(),
+                    value,
+                });
+            }
+        }
+        map_symbols.sort_by(|a, b| (a.value, &a.name).cmp(&(b.value, &b.name)));
+
+        let mut image = vec![0; address];
+        for (index, object) in objects.iter().enumerate() {
+            for (number, section) in object.sections.iter().enumerate() {
+                let base = bases[&(index, number)];
+                image[base..base + section.data.len()].copy_from_slice(&section.data);
+
+                for relocation in &section.relocations {
+                    if relocation.offset >= section.data.len() {
+                        return error(format!(
+                            "relocation at offset {} is outside of section {} in {}",
+                            relocation.offset, section.name, object.name
+                        ));
+                    }
+                    let target = match &relocation.target {
+                        Target::Section(section) => section_base(&bases, index, *section, object)? as i64,
+                        Target::Symbol(name) => {
+                            let known = object.imports.contains(name)
+                                || object.exports.iter().any(|export| &export.name == name);
+                            match symbols.get(name.as_str()) {
+                                Some(value) if known => *value,
+                                _ => {
+                                    return error(format!(
+                                        "symbol {} used in {} is neither imported nor exported",
+                                        name, object.name
+                                    ))
+                                }
+                            }
+                        }
+                    };
+                    let value = target + relocation.addend;
+                    if !(-128..=255).contains(&value) {
+                        return error(format!(
+                            "relocated value {} at {:02X} in {} does not fit in a byte",
+                            value,
+                            base + relocation.offset,
+                            object.name
+                        ));
+                    }
+                    image[base + relocation.offset] = value as u8;
+                }
+            }
+        }
+
+        Ok(Linked {
+            image,
+            map: LinkMap {
+                placements,
+                symbols: map_symbols,
+            },
+        })
+    }
+
+    // The objects to link: all given objects and the library objects
+    // needed to resolve their imports.
+    fn select(&self) -> Result<Vec<&Object>, LinkError> {
+        let mut selected: Vec<&Object> = Vec::new();
+        let mut defined: HashMap<&str, &str> = HashMap::new();
+        let mut pending: Vec<&Object> = self.objects.iter().collect();
+
+        loop {
+            for object in pending.drain(..) {
+                for export in &object.exports {
+                    if let Some(other) = defined.insert(&export.name, &object.name) {
+                        return error(format!(
+                            "symbol {} is defined in both {} and {}",
+                            export.name, other, object.name
+                        ));
+                    }
+                }
+                selected.push(object);
+            }
+
+            let missing = selected
+                .iter()
+                .flat_map(|object| object.imports.iter().map(move |import| (import, object)))
+                .find(|(import, _)| !defined.contains_key(import.as_str()));
+            let (import, importer) = match missing {
+                Some(missing) => missing,
+                None => return Ok(selected),
+            };
+
+            let member = self
+                .libraries
+                .iter()
+                .flat_map(|library| library.objects.iter())
+                .find(|object| object.exports.iter().any(|export| &export.name == import));
+            match member {
+                Some(member) => pending.push(member),
+                None => {
+                    return error(format!(
+                        "undefined symbol {} imported by {}",
+                        import, importer.name
+                    ))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::{tokenize, Assembler};
+    use object::Relocation;
+
+    fn object(name: &str, source: &str) -> Object {
+        let tokens = tokenize(source).unwrap();
+        let mut object = Assembler::new().assemble_object(&tokens).unwrap();
+        object.name = name.to_string();
+        object
+    }
+
+    // Sections with the same name end up together: both texts, then data.
+    #[test]
+    fn links_objects_and_library_members() {
+        let main = object(
+            "main.o",
+            "import putc\npush message\npush putc\nhalt\nsection data\nmessage: db 7\n",
+        );
+        let putc = object("putc.o", "export putc\nputc: push 1\nhalt\n");
+        let unused = object("unused.o", "export spare\nspare: halt\n");
+        let library = Library {
+            objects: vec![unused, putc],
+        };
+
+        let linked = Linker::new().object(main).library(library).link().unwrap();
+        assert_eq!(linked.image, vec![0x03, 8, 0x03, 5, 0x00, 0x03, 1, 0x00, 7]);
+        let objects: Vec<&str> = linked
+            .map
+            .placements
+            .iter()
+            .map(|placement| placement.object.as_str())
+            .collect();
+        assert_eq!(objects, vec!["main.o", "putc.o", "main.o"]);
+        assert_eq!(
+            linked.map.symbols,
+            vec![MapSymbol {
+                name: "putc".to_string(),
+                object: "putc.o".to_string(),
+                value: 5,
+            }]
+        );
+    }
+
+    #[test]
+    fn unresolved_symbols() {
+        let main = object("main.o", "import putc\npush putc\n");
+        let err = Linker::new().object(main).link().unwrap_err();
+        assert_eq!(err.to_string(), "undefined symbol putc imported by main.o");
+
+        let a = object("a.o", "export x\nx: halt\n");
+        let b = object("b.o", "export x\nx: halt\n");
+        let err = Linker::new().object(a).object(b).link().unwrap_err();
+        assert_eq!(err.to_string(), "symbol x is defined in both a.o and b.o");
+    }
+
+    #[test]
+    fn rejects_malformed_objects() {
+        let link = |object: Object| Linker::new().object(object).link().unwrap_err().to_string();
+
+        // a relocation against a symbol the object never imported
+        let mut main = object("main.o", "push 0\n");
+        main.sections[0].relocations.push(Relocation {
+            offset: 1,
+            target: Target::Symbol("putc".to_string()),
+            addend: 0,
+        });
+        assert_eq!(link(main.clone()), "symbol putc used in main.o is neither imported nor exported");
+
+        main.sections[0].relocations[0].target = Target::Section(3);
+        assert_eq!(link(main.clone()), "unknown section 3 in main.o");
+
+        main.sections[0].relocations[0].offset = 2;
+        assert_eq!(link(main), "relocation at offset 2 is outside of section text in main.o");
+
+        let mut main = object("main.o", "export start\nstart: halt\n");
+        main.exports[0].section = Some(1);
+        assert_eq!(link(main), "unknown section 1 in main.o");
+
+        let big = object("big.o", "ds 200\nsection data\nds 100\n");
+        assert_eq!(link(big), "image of 300 bytes does not fit in memory");
+    }
+}
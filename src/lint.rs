@@ -0,0 +1,546 @@
+//! Static checks for assembled programs, for mistakes that otherwise only
+//! show up as odd behaviour in the emulator.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use assembler::{opens_scope, qualify, Token};
+use ast::{DirectiveKind, ItemKind, Operand, Program};
+use builder::Config;
+use disasm;
+use expr::Expr;
+use listing::Listing;
+use stack::{self, ProblemKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lint {
+    FallThroughIntoData,
+    Unreachable,
+    MissingHalt,
+    StoreToRegister,
+    DuplicateLabel,
+    UnusedLabel,
+    StackCollision,
+    StackUnderflow,
+    StackMismatch,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub lint: Lint,
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// One decoded instruction of the program.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Instruction {
+    pub address: usize,
+    pub opcode: u8,
+    pub operand: Option<u8>,
+    pub position: usize,
+}
+
+impl Instruction {
+    pub fn len(&self) -> usize {
+        disasm::instruction_length(self.opcode)
+    }
+}
+
+/// The instructions of a program and where execution can go from each.
+/// Jump targets are only known when the `jp` directly follows the `push`
+/// of its target, which is how the assembler writes them.
+pub(crate) struct Flow {
+    pub instructions: Vec<Instruction>,
+    /// Indexes of the instructions execution can continue with.
+    pub successors: Vec<Vec<usize>>,
+    /// Whether a `jp` has a target only known at run time.
+    pub dynamic: bool,
+    /// The instructions execution can start from: the entry point and, with
+    /// dynamic jumps, every instruction whose address is pushed.
+    pub roots: Vec<usize>,
+    by_address: HashMap<usize, usize>,
+}
+
+impl Flow {
+    pub fn new(listing: &Listing, entry: usize) -> Flow {
+        let mut instructions = Vec::new();
+        for line in listing.lines.iter().filter(|line| line.code) {
+            let mut offset = 0;
+            while offset < line.bytes.len() {
+                let opcode = line.bytes[offset];
+                let len = disasm::instruction_length(opcode);
+                instructions.push(Instruction {
+                    address: line.address + offset,
+                    opcode,
+                    operand: if len > 1 { line.bytes.get(offset + 1).cloned() } else { None },
+                    position: line.position,
+                });
+                offset += len;
+            }
+        }
+        let by_address: HashMap<usize, usize> = instructions
+            .iter()
+            .enumerate()
+            .map(|(index, instruction)| (instruction.address, index))
+            .collect();
+
+        let mut flow = Flow {
+            successors: Vec::new(),
+            dynamic: false,
+            roots: by_address.get(&entry).cloned().into_iter().collect(),
+            instructions,
+            by_address,
+        };
+        for index in 0..flow.instructions.len() {
+            let successors = flow.find_successors(index);
+            flow.successors.push(successors);
+        }
+        if flow.dynamic {
+            for index in 0..flow.instructions.len() {
+                if let Some(target) = flow.pushed(index + 1).and_then(|value| flow.at(value.wrapping_add(1) as usize)) {
+                    if !flow.roots.contains(&target) {
+                        flow.roots.push(target);
+                    }
+                }
+            }
+        }
+        flow
+    }
+
+    /// The instruction starting at `address`.
+    pub fn at(&self, address: usize) -> Option<usize> {
+        self.by_address.get(&address).cloned()
+    }
+
+    /// The value pushed right before the instruction at `index`, if it
+    /// directly follows a `push`.
+    pub fn pushed(&self, index: usize) -> Option<u8> {
+        let previous = self.instructions.get(index.checked_sub(1)?)?;
+        let instruction = self.instructions.get(index)?;
+        if previous.opcode == 0x03 && previous.address + previous.len() == instruction.address {
+            previous.operand
+        } else {
+            None
+        }
+    }
+
+    fn find_successors(&mut self, index: usize) -> Vec<usize> {
+        let instruction = self.instructions[index];
+        let next = self.at(instruction.address + instruction.len());
+        match instruction.opcode {
+            0x00 => Vec::new(),
+            0x0A => {
+                let mut successors = Vec::new();
+                // conditions past `neq` never jump
+                if instruction.operand.is_none_or(|condition| condition <= 6) {
+                    // `jp` continues after the address it jumped to
+                    match self.pushed(index) {
+                        Some(target) => successors.extend(self.at(target.wrapping_add(1) as usize)),
+                        None => self.dynamic = true,
+                    }
+                }
+                if instruction.operand != Some(0) {
+                    successors.extend(next);
+                }
+                successors
+            }
+            opcode if disasm::mnemonic(opcode).is_none() => Vec::new(),
+            _ => next.into_iter().collect(),
+        }
+    }
+
+    /// Marks the instructions execution can reach from the roots.
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reached = vec![false; self.instructions.len()];
+        let mut pending = self.roots.clone();
+        while let Some(index) = pending.pop() {
+            if reached[index] {
+                continue;
+            }
+            reached[index] = true;
+            pending.extend(self.successors[index].iter().cloned());
+        }
+        reached
+    }
+}
+
+/// Checks a program and its listing. `entry` is the address execution
+/// starts at; labels there are not reported as unused.
+pub fn lint(program: &Program, listing: &Listing, entry: usize) -> Vec<Warning> {
+    let mut warnings = check_labels(program, listing, entry);
+    warnings.extend(check_flow(listing, entry));
+    warnings.sort_by_key(|warning| warning.position);
+    warnings
+}
+
+fn warning(lint: Lint, position: usize, message: String) -> Warning {
+    Warning {
+        lint,
+        position,
+        message,
+    }
+}
+
+fn check_labels(program: &Program, listing: &Listing, entry: usize) -> Vec<Warning> {
+    // lines excluded by conditionals are not in the listing
+    let listed: HashSet<usize> = listing.lines.iter().map(|line| line.position).collect();
+    let addresses: HashMap<&str, i64> = listing
+        .symbols
+        .iter()
+        .filter(|symbol| symbol.label)
+        .map(|symbol| (symbol.name.as_ref(), symbol.value))
+        .collect();
+
+    let mut warnings = Vec::new();
+    let mut defined = HashSet::new();
+    let mut definitions = Vec::new();
+    let mut used = HashSet::new();
+    let mut scope = String::new();
+
+    for items in program.items.chunk_by(|a, b| a.line == b.line) {
+        let labels: Vec<&Token> = items
+            .iter()
+            .filter_map(|item| match item.kind {
+                ItemKind::Label(token) => Some(token),
+                _ => None,
+            })
+            .collect();
+        let statement = items.iter().find(|item| matches!(item.kind, ItemKind::Instruction(_) | ItemKind::Directive(_)));
+        let position = match (statement, labels.first()) {
+            (Some(item), _) => item.span.start,
+            (None, Some(label)) => label.position(),
+            _ => continue,
+        };
+        let conditional = match statement.map(|item| &item.kind) {
+            Some(ItemKind::Directive(directive)) => directive.kind.is_conditional(),
+            _ => false,
+        };
+        if !conditional && !listed.contains(&position) {
+            continue;
+        }
+
+        if let Some(label) = labels.iter().filter_map(|label| opens_scope(label)).next_back() {
+            scope = label.to_string();
+        }
+        let mut names: Vec<(&str, usize)> = labels
+            .iter()
+            .filter_map(|label| match label {
+                Token::Word(position, name) => Some((name.as_ref(), *position)),
+                _ => None,
+            })
+            .collect();
+
+        let mut exprs = Vec::new();
+        match statement.map(|item| &item.kind) {
+            Some(ItemKind::Instruction(instruction)) => {
+                for operand in &instruction.operands {
+                    if let Operand::Expr(expr) = operand {
+                        exprs.push(expr);
+                    }
+                }
+            }
+            Some(ItemKind::Directive(directive)) => match &directive.kind {
+                DirectiveKind::Word { name, value } => {
+                    names.extend(name.map(|name| (name, position)));
+                    exprs.push(value);
+                }
+                DirectiveKind::Bytes { name, values } => {
+                    names.extend(name.map(|name| (name, position)));
+                    exprs.extend(values.iter().filter_map(|value| match value {
+                        ::ast::Data::Expr(expr) => Some(expr),
+                        _ => None,
+                    }));
+                }
+                DirectiveKind::Asciz { name, .. }
+                | DirectiveKind::Pstr { name, .. } => names.extend(name.map(|name| (name, position))),
+                DirectiveKind::Reserve { name, size } => {
+                    names.extend(name.map(|name| (name, position)));
+                    exprs.push(size);
+                }
+                DirectiveKind::Org(expr) | DirectiveKind::Align(expr) | DirectiveKind::Equ(_, expr) | DirectiveKind::If(expr) => {
+                    exprs.push(expr)
+                }
+                DirectiveKind::Ifdef(name) | DirectiveKind::Ifndef(name) => {
+                    used.insert(qualify(name, &scope));
+                }
+                DirectiveKind::Export(tokens) => {
+                    for token in tokens {
+                        if let Token::Word(_, name) = token {
+                            used.insert(qualify(name, &scope));
+                        }
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+        for expr in exprs {
+            symbols(expr, &mut |name| {
+                used.insert(qualify(name, &scope));
+            });
+        }
+
+        for (name, position) in names {
+            let qualified = qualify(name, &scope);
+            if defined.insert(qualified.clone()) {
+                definitions.push((qualified, position));
+            } else {
+                warnings.push(warning(
+                    Lint::DuplicateLabel,
+                    position,
+                    format!("label {} is defined more than once", qualified),
+                ));
+            }
+        }
+    }
+
+    // labels in macro bodies are renamed to `name@N` for every use of the
+    // macro, so report them by their name in the body, and only if no use
+    // of the macro needs them
+    let unused: Vec<(&str, usize)> = definitions
+        .iter()
+        .filter(|(name, _)| {
+            let at_entry = addresses.get(name.as_str()) == Some(&(entry as i64));
+            !used.contains(name) && !at_entry
+        })
+        .map(|(name, position)| (unmangle(name), *position))
+        .collect();
+    let needed: HashSet<(&str, usize)> = definitions
+        .iter()
+        .map(|(name, position)| (unmangle(name), *position))
+        .filter(|definition| !unused.contains(definition))
+        .collect();
+    let mut reported = HashSet::new();
+    for (name, position) in unused {
+        if !needed.contains(&(name, position)) && reported.insert((name, position)) {
+            warnings.push(warning(Lint::UnusedLabel, position, format!("label {} is never used", name)));
+        }
+    }
+    warnings
+}
+
+// The name of a label before macro expansion renamed it.
+fn unmangle(name: &str) -> &str {
+    match name.rfind('@') {
+        Some(at) if name[at + 1..].bytes().all(|byte| byte.is_ascii_digit()) => &name[..at],
+        _ => name,
+    }
+}
+
+// Calls `found` with every symbol `expr` refers to.
+fn symbols(expr: &Expr, found: &mut dyn FnMut(&str)) {
+    match expr {
+        Expr::Number(..) => {}
+        Expr::Symbol(_, name) => found(name),
+        Expr::Unary(_, _, operand) => symbols(operand, found),
+        Expr::Binary(_, _, lhs, rhs) => {
+            symbols(lhs, found);
+            symbols(rhs, found);
+        }
+    }
+}
+
+fn check_flow(listing: &Listing, entry: usize) -> Vec<Warning> {
+    let config = Config::default();
+    let flow = Flow::new(listing, entry);
+    let reached = flow.reachable();
+    let mut warnings = Vec::new();
+
+    let mut data = HashMap::new();
+    let mut end = 0;
+    for line in listing.lines.iter().filter(|line| !line.bytes.is_empty()) {
+        if !line.code {
+            for address in line.address..line.address + line.bytes.len() {
+                data.insert(address, line.position);
+            }
+        }
+        end = end.max(line.address + line.bytes.len());
+    }
+
+    let mut halts = false;
+    for (index, instruction) in flow.instructions.iter().enumerate() {
+        if !reached[index] {
+            // one warning for a run of unreachable instructions
+            if index == 0 || reached[index - 1] {
+                warnings.push(warning(
+                    Lint::Unreachable,
+                    instruction.position,
+                    format!("instruction at {:02X} is never executed", instruction.address),
+                ));
+            }
+            continue;
+        }
+
+        let next = instruction.address + instruction.len();
+        let falls_through = match instruction.opcode {
+            0x00 => {
+                halts = true;
+                false
+            }
+            0x0A => instruction.operand != Some(0),
+            opcode => disasm::mnemonic(opcode).is_some(),
+        };
+        if falls_through && flow.at(next).is_none() {
+            if data.contains_key(&next) {
+                warnings.push(warning(
+                    Lint::FallThroughIntoData,
+                    instruction.position,
+                    format!("execution falls through into data at {:02X}", next),
+                ));
+            } else {
+                warnings.push(warning(
+                    Lint::MissingHalt,
+                    instruction.position,
+                    format!("execution runs past the end of the code at {:02X} without a halt", next),
+                ));
+            }
+        }
+
+        if let (0x02, Some(address)) = (instruction.opcode, flow.pushed(index)) {
+            if address as usize >= config.sp() {
+                let register = if address as usize == config.sp() { "SP" } else { "PC" };
+                warnings.push(warning(
+                    Lint::StoreToRegister,
+                    instruction.position,
+                    format!("store to {:02X} overwrites the {} register", address, register),
+                ));
+            }
+        }
+    }
+
+    if let (false, Some(root)) = (halts || flow.dynamic, flow.roots.first()) {
+        warnings.push(warning(
+            Lint::MissingHalt,
+            flow.instructions[*root].position,
+            "no path through the program reaches a halt".to_string(),
+        ));
+    }
+
+    warnings.extend(check_stack(&flow, end, &config));
+    warnings
+}
+
+// Reports stack problems and stack growth that reaches the program.
+fn check_stack(flow: &Flow, end: usize, config: &Config) -> Vec<Warning> {
+    let analysis = stack::analyze_flow(flow);
+    let mut warnings: Vec<Warning> = analysis
+        .problems
+        .iter()
+        .map(|problem| {
+            let lint = match problem.kind {
+                ProblemKind::Underflow => Lint::StackUnderflow,
+                ProblemKind::Mismatch => Lint::StackMismatch,
+            };
+            warning(lint, problem.position, problem.message.clone())
+        })
+        .collect();
+
+    let room = (config.stack_base as usize + 1).saturating_sub(end);
+    if let Some(deepest) = analysis.deepest.filter(|deepest| deepest.depth.max > room) {
+        let depth = match analysis.max {
+            Some(max) => format!("to {} bytes", max),
+            None => "without bound".to_string(),
+        };
+        warnings.push(warning(
+            Lint::StackCollision,
+            deepest.position,
+            format!("stack grows {} here and would overwrite the program below {:02X}", depth, end),
+        ));
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::{tokenize, Assembler};
+    use ast;
+    use listing::ListingLine;
+    use macros;
+
+    fn check(source: &str) -> Vec<(Lint, String)> {
+        let tokens = macros::expand(tokenize(source).unwrap()).unwrap().tokens;
+        let program = ast::parse(&tokens).unwrap();
+        let (_, listing) = Assembler::new().encode_with_listing(&program).unwrap();
+        lint(&program, &listing, 0)
+            .into_iter()
+            .map(|warning| (warning.lint, warning.message))
+            .collect()
+    }
+
+    fn code(bytes: &[u8]) -> Listing {
+        Listing {
+            lines: vec![ListingLine {
+                position: 0,
+                address: 0,
+                bytes: bytes.to_vec(),
+                code: true,
+                pseudo: false,
+            }],
+            symbols: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn clean_program() {
+        assert_eq!(check("start: push 1\npush 0\njp gt\nhalt\n"), vec![]);
+    }
+
+    #[test]
+    fn labels() {
+        let warnings = check("a: push a\nb: halt\na: db 1\n");
+        assert_eq!(
+            warnings,
+            vec![
+                (Lint::UnusedLabel, "label b is never used".to_string()),
+                (Lint::DuplicateLabel, "label a is defined more than once".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn macro_labels() {
+        let source = "macro wait\nagain: push again\nskip: push 0\njp eq\nendm\nwait\nwait\nhalt\n";
+        let unused: Vec<String> = check(source)
+            .into_iter()
+            .filter(|(lint, _)| *lint == Lint::UnusedLabel)
+            .map(|(_, message)| message)
+            .collect();
+        assert_eq!(unused, vec!["label skip is never used".to_string()]);
+        assert_eq!(unmangle("loop@12"), "loop");
+        assert_eq!(unmangle("mail@home"), "mail@home");
+    }
+
+    #[test]
+    fn control_flow() {
+        let warnings = check("push 5\nhalt\npush 1\ndead: push 2\n");
+        assert!(warnings.contains(&(Lint::Unreachable, "instruction at 03 is never executed".to_string())));
+
+        let warnings = check("push 1\ndb 2\n");
+        assert!(warnings.contains(&(Lint::FallThroughIntoData, "execution falls through into data at 02".to_string())));
+
+        let warnings = check("loop: push loop - 1\njp\n");
+        assert!(warnings.contains(&(Lint::MissingHalt, "no path through the program reaches a halt".to_string())));
+
+        let warnings = check("push 7\npush 0xFE\nstore\nhalt\n");
+        assert!(warnings.contains(&(Lint::StoreToRegister, "store to FE overwrites the SP register".to_string())));
+    }
+
+    #[test]
+    fn conditions_past_neq_never_jump() {
+        // push 6; jp 7; halt: the jump only pops its target and falls through
+        let flow = Flow::new(&code(&[0x03, 0x06, 0x0A, 0x07, 0x00]), 0);
+        assert_eq!(flow.successors[1], vec![2]);
+        assert_eq!(disasm::stack_effect(0x0A, Some(7)), Some((1, 0)));
+        assert_eq!(disasm::stack_effect(0x0A, Some(6)), Some((2, 0)));
+        assert_eq!(disasm::stack_effect(0x0A, Some(0)), Some((1, 0)));
+        assert!(check_flow(&code(&[0x03, 0x06, 0x0A, 0x07, 0x00]), 0).is_empty());
+    }
+}
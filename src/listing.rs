@@ -0,0 +1,142 @@
+use std::fmt::Write;
+
+use disasm;
+use source::SourceMap;
+
+const BYTES_PER_ROW: usize = 4;
+
+/// The bytes one source line assembled to. Lines that only reserve space or
+/// move the address (`ds`, `org`, `align`) have no bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListingLine {
+    pub position: usize,
+    pub address: usize,
+    pub bytes: Vec<u8>,
+    /// Whether the bytes are an instruction rather than data.
+    pub code: bool,
+    /// Whether the line is a pseudo-instruction that expanded to several
+    /// instructions.
+    pub pseudo: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub value: i64,
+    /// Labels name addresses; other symbols are `equ` constants or defines.
+    pub label: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Listing {
+    pub lines: Vec<ListingLine>,
+    /// Named symbols sorted by name; local labels are qualified with the
+    /// label they belong to.
+    pub symbols: Vec<Symbol>,
+}
+
+impl Listing {
+    /// Renders the listing with the source text of every line, e.g.
+    ///
+    /// ```text
+    /// 00  03 05        push 5
+    /// ```
+    ///
+    /// followed by the symbol table. Pseudo-instructions are followed by the
+    /// instructions they expanded to.
+    pub fn to_text(&self, sources: &SourceMap) -> String {
+        let mut out = String::new();
+
+        for line in &self.lines {
+            let text = match sources.lookup(line.position) {
+                Some((file, local)) => file.line_text(local),
+                None => "",
+            };
+
+            if line.pseudo {
+                writeln!(out, "{:02X}  {:<12} {}", line.address, "", text).unwrap();
+                let mut offset = 0;
+                while offset < line.bytes.len() {
+                    let (instruction, len) = disasm::disassemble(&line.bytes, offset);
+                    let len = len.min(line.bytes.len() - offset);
+                    let bytes = &line.bytes[offset..offset + len];
+                    writeln!(out, "{:02X}  {:<12}   {}", line.address + offset, hex(bytes), instruction).unwrap();
+                    offset += len;
+                }
+                continue;
+            }
+
+            let mut rows = line.bytes.chunks(BYTES_PER_ROW);
+            let first = rows.next().unwrap_or(&[]);
+            writeln!(out, "{:02X}  {:<12} {}", line.address, hex(first), text).unwrap();
+            for (i, row) in rows.enumerate() {
+                let address = line.address + (i + 1) * BYTES_PER_ROW;
+                writeln!(out, "{:02X}  {}", address, hex(row)).unwrap();
+            }
+        }
+
+        out.push_str("\nSymbols:\n");
+        for symbol in &self.symbols {
+            writeln!(out, "{:<16} {:02X}", symbol.name, symbol.value).unwrap();
+        }
+        out
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    bytes.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::{tokenize, Assembler};
+    use std::path::Path;
+
+    fn text(source: &str) -> String {
+        let (_, listing) = Assembler::new()
+            .assemble_with_listing(&tokenize(source).unwrap())
+            .unwrap();
+        let mut sources = SourceMap::new();
+        sources.add(Path::new("main.s"), source);
+        listing.to_text(&sources)
+    }
+
+    #[test]
+    fn rows_and_symbols() {
+        let source = "equ WIDTH 2\nstart: push text\nds 1\ntext: db \"hello\", 0\nend: halt\n";
+        let expected = "\
+00               equ WIDTH 2
+00  03 03        start: push text
+02               ds 1
+03  68 65 6C 6C  text: db \"hello\", 0
+07  6F 00
+09  00           end: halt
+
+Symbols:
+WIDTH            02
+end              09
+start            00
+text             03
+";
+        assert_eq!(text(source), expected);
+    }
+
+    #[test]
+    fn shows_pseudo_instruction_expansion() {
+        let expected = "\
+00               inc 0x40
+00  03 40          push 0x40
+02  01             load
+03  03 01          push 0x01
+05  05             add
+06  03 40          push 0x40
+08  02             store
+09  00           halt
+
+Symbols:
+";
+        assert_eq!(text("inc 0x40\nhalt\n"), expected);
+    }
+}
@@ -0,0 +1,412 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ptr;
+
+use assembler::Token;
+
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct MacroError {
+    message: String,
+    position: usize,
+    definition: Option<usize>,
+}
+
+impl MacroError {
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn definition(&self) -> Option<usize> {
+        self.definition
+    }
+}
+
+impl fmt::Display for MacroError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)?;
+        if let Some(definition) = self.definition {
+            write!(f, " (macro defined at position {})", definition)?;
+        }
+        Ok(())
+    }
+}
+
+/// One use of a macro. `parent` is set when the use itself comes from
+/// another macro's body.
+#[derive(Debug, Clone)]
+pub struct Expansion {
+    pub name: String,
+    pub definition: usize,
+    pub use_site: usize,
+    pub parent: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Expanded {
+    pub tokens: Vec<Token>,
+    pub expansions: Vec<Expansion>,
+    origins: Vec<Option<usize>>,
+}
+
+impl Expanded {
+    /// Macro expansions that produced `token`, innermost first. `token` must
+    /// be one of `self.tokens`, e.g. the token of a `ParserError`.
+    pub fn backtrace(&self, token: &Token) -> Vec<&Expansion> {
+        let mut chain = Vec::new();
+        let index = match self.tokens.iter().position(|other| ptr::eq(other, token)) {
+            Some(index) => index,
+            None => return chain,
+        };
+
+        let mut origin = self.origins[index];
+        while let Some(id) = origin {
+            chain.push(&self.expansions[id]);
+            origin = self.expansions[id].parent;
+        }
+        chain
+    }
+
+    fn push(&mut self, token: Token, origin: Option<usize>) {
+        self.tokens.push(token);
+        self.origins.push(origin);
+    }
+}
+
+struct Macro {
+    name: String,
+    params: Vec<String>,
+    body: Vec<Token>,
+    position: usize,
+}
+
+/// Expands `macro name params ... endm` definitions in `tokens`.
+///
+/// Arguments are comma separated and substituted for the parameter names
+/// in the body. Labels defined inside a body are renamed for every expansion
+/// so a macro can be used more than once. Lines outside of macros are moved
+/// to the output as they are.
+pub fn expand(tokens: Vec<Token>) -> Result<Expanded, MacroError> {
+    let lengths: Vec<usize> = split_lines(&tokens).iter().map(|line| line.len()).collect();
+    let mut tokens = tokens.into_iter();
+    let mut lines = lengths.into_iter().map(|len| tokens.by_ref().take(len).collect::<Vec<Token>>());
+    let mut macros: HashMap<String, Macro> = HashMap::new();
+    let mut out = Expanded::default();
+
+    while let Some(line) = lines.next() {
+        if is_keyword(&line, "macro") {
+            let mut definition = parse_header(&line)?;
+            loop {
+                let body_line = match lines.next() {
+                    Some(body_line) => body_line,
+                    None => {
+                        return Err(MacroError {
+                            message: format!("macro {} is missing endm", definition.name),
+                            position: definition.position,
+                            definition: None,
+                        })
+                    }
+                };
+
+                if is_keyword(&body_line, "endm") {
+                    break;
+                }
+                if is_keyword(&body_line, "macro") {
+                    return Err(MacroError {
+                        message: "nested macro definition".to_string(),
+                        position: body_line[0].position(),
+                        definition: Some(definition.position),
+                    });
+                }
+                definition.body.extend(body_line);
+            }
+            macros.insert(definition.name.to_lowercase(), definition);
+        } else if is_keyword(&line, "endm") {
+            return Err(MacroError {
+                message: "endm without macro".to_string(),
+                position: line[0].position(),
+                definition: None,
+            });
+        } else if find_use(&line, &macros).is_some() {
+            expand_line(&line, &macros, &mut out, None, 0)?;
+        } else {
+            for token in line {
+                out.push(token, None);
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+// The number of tokens of the labels in front of a macro use on `line`,
+// and the macro used.
+fn find_use<'m>(line: &[Token], macros: &'m HashMap<String, Macro>) -> Option<(usize, &'m Macro)> {
+    if macros.is_empty() {
+        return None;
+    }
+    let mut i = 0;
+    while let (Some(Token::Word(..)), Some(Token::Colon(_))) = (line.get(i), line.get(i + 1)) {
+        i += 2;
+    }
+    match line.get(i) {
+        Some(Token::Word(_, word)) => macros.get(&word.to_lowercase()).map(|definition| (i, definition)),
+        _ => None,
+    }
+}
+
+fn expand_line(
+    line: &[Token],
+    macros: &HashMap<String, Macro>,
+    out: &mut Expanded,
+    parent: Option<usize>,
+    depth: usize,
+) -> Result<(), MacroError> {
+    let (i, definition) = match find_use(line, macros) {
+        Some(found) => found,
+        None => {
+            copy_line(line, out, parent);
+            return Ok(());
+        }
+    };
+    let position = line[i].position();
+
+    if depth >= MAX_DEPTH {
+        return Err(MacroError {
+            message: format!("macro {} nested more than {} levels deep", definition.name, MAX_DEPTH),
+            position,
+            definition: Some(definition.position),
+        });
+    }
+
+    let args = split_args(&line[i + 1..]);
+    if args.len() != definition.params.len() {
+        return Err(MacroError {
+            message: format!(
+                "macro {} expects {} arguments, got {}",
+                definition.name,
+                definition.params.len(),
+                args.len()
+            ),
+            position,
+            definition: Some(definition.position),
+        });
+    }
+
+    // keep labels in front of the use on a line of their own
+    if i > 0 {
+        for token in &line[..i] {
+            out.push(token.clone(), parent);
+        }
+        out.push(Token::EOL(position), parent);
+    }
+
+    let id = out.expansions.len();
+    out.expansions.push(Expansion {
+        name: definition.name.clone(),
+        definition: definition.position,
+        use_site: position,
+        parent,
+    });
+
+    let body = instantiate(definition, &args, id);
+    for body_line in split_lines(&body) {
+        expand_line(body_line, macros, out, Some(id), depth + 1)?;
+    }
+
+    Ok(())
+}
+
+fn copy_line(line: &[Token], out: &mut Expanded, origin: Option<usize>) {
+    for token in line {
+        out.push(token.clone(), origin);
+    }
+}
+
+fn instantiate(definition: &Macro, args: &[&[Token]], id: usize) -> Vec<Token> {
+    let mut labels = HashSet::new();
+    for line in split_lines(&definition.body) {
+        let mut i = 0;
+        while let (Some(Token::Word(_, label)), Some(Token::Colon(_))) = (line.get(i), line.get(i + 1)) {
+            labels.insert(label.clone());
+            i += 2;
+        }
+    }
+
+    let mut body = Vec::new();
+    for token in &definition.body {
+        match token {
+            Token::Word(position, word) => {
+                if let Some(index) = definition.params.iter().position(|param| param == word) {
+                    body.extend(args[index].iter().cloned());
+                } else if labels.contains(word) {
+                    body.push(Token::Word(*position, format!("{}@{}", word, id)));
+                } else {
+                    body.push(token.clone());
+                }
+            }
+            _ => body.push(token.clone()),
+        }
+    }
+    body
+}
+
+fn parse_header(line: &[Token]) -> Result<Macro, MacroError> {
+    let position = line[0].position();
+    let name = match line.get(1) {
+        Some(Token::Word(_, name)) => name.clone(),
+        Some(token) => {
+            return Err(MacroError {
+                message: "expected macro name".to_string(),
+                position: token.position(),
+                definition: None,
+            })
+        }
+        None => {
+            return Err(MacroError {
+                message: "expected macro name".to_string(),
+                position,
+                definition: None,
+            })
+        }
+    };
+
+    let mut params = Vec::new();
+    for param in split_args(&line[2..]) {
+        match param {
+            [Token::Word(_, param)] => params.push(param.clone()),
+            _ => {
+                return Err(MacroError {
+                    message: "expected parameter name".to_string(),
+                    position: param.first().map_or(position, Token::position),
+                    definition: None,
+                })
+            }
+        }
+    }
+
+    Ok(Macro {
+        name,
+        params,
+        body: Vec::new(),
+        position,
+    })
+}
+
+fn is_keyword(line: &[Token], keyword: &str) -> bool {
+    match line.first() {
+        Some(Token::Word(_, word)) => word.eq_ignore_ascii_case(keyword),
+        _ => false,
+    }
+}
+
+// Splits comma separated arguments up to the end of the line or a comment,
+// ignoring commas nested in parentheses.
+fn split_args(tokens: &[Token]) -> Vec<&[Token]> {
+    let end = tokens
+        .iter()
+        .position(|token| matches!(token, Token::EOL(_) | Token::Comment(..)))
+        .unwrap_or(tokens.len());
+    let tokens = &tokens[..end];
+
+    let mut args = Vec::new();
+    if tokens.is_empty() {
+        return args;
+    }
+
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Operator(_, op) if op == "(" => depth += 1,
+            Token::Operator(_, op) if op == ")" => depth -= 1,
+            Token::Comma(_) if depth == 0 => {
+                args.push(&tokens[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    args.push(&tokens[start..]);
+    args
+}
+
+fn split_lines(tokens: &[Token]) -> Vec<&[Token]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        if let Token::EOL(_) = token {
+            lines.push(&tokens[start..=i]);
+            start = i + 1;
+        }
+    }
+    if start < tokens.len() {
+        lines.push(&tokens[start..]);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::{parse, tokenize};
+
+    fn assemble(source: &str) -> Vec<u8> {
+        let expanded = expand(tokenize(source).unwrap()).unwrap();
+        parse(&expanded.tokens).unwrap()
+    }
+
+    fn error(source: &str) -> String {
+        expand(tokenize(source).unwrap()).unwrap_err().message
+    }
+
+    #[test]
+    fn substitutes_arguments() {
+        let source = "macro pair a, b\npush a\npush b\nendm\nPAIR 1, (2 + 3)\nhalt\n";
+        assert_eq!(assemble(source), vec![0x03, 1, 0x03, 5, 0x00]);
+    }
+
+    #[test]
+    fn renames_labels_per_expansion() {
+        let source = "macro spin\nloop: push loop\nendm\nspin\nspin\n";
+        let expanded = expand(tokenize(source).unwrap()).unwrap();
+        let words: Vec<&str> = expanded
+            .tokens
+            .iter()
+            .filter_map(|token| match token {
+                Token::Word(_, word) if word.contains('@') => Some(word.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(words, vec!["loop@0", "loop@0", "loop@1", "loop@1"]);
+        assert_eq!(parse(&expanded.tokens).unwrap(), vec![0x03, 0, 0x03, 2]);
+    }
+
+    #[test]
+    fn backtrace_follows_nested_uses() {
+        let source = "macro inner\nfrob\nendm\nmacro outer\ninner\nendm\nouter\n";
+        let expanded = expand(tokenize(source).unwrap()).unwrap();
+        let err = parse(&expanded.tokens).unwrap_err();
+        let chain: Vec<&str> = expanded
+            .backtrace(err.token())
+            .iter()
+            .map(|expansion| expansion.name.as_str())
+            .collect();
+        assert_eq!(chain, vec!["inner", "outer"]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("macro m\npush 1\n"), "macro m is missing endm");
+        assert_eq!(error("endm\n"), "endm without macro");
+        assert_eq!(error("macro m\nmacro n\nendm\n"), "nested macro definition");
+        assert_eq!(error("macro m a\nendm\nm 1, 2\n"), "macro m expects 1 arguments, got 2");
+        assert_eq!(error("macro m 1\nendm\n"), "expected parameter name");
+        assert_eq!(error("macro m\nm\nendm\nm\n"), "macro m nested more than 64 levels deep");
+    }
+}
@@ -1,19 +1,289 @@
 extern crate mcpu;
 
-fn main() {
-    let tokens = mcpu::assembler::tokenize(
-        "push 0x05\njp\ndw sum 0x0\ndw i 0xa\npush i\nload\npush sum\nload\nadd\npush sum\nstore\npush 0x1\npush i\nload\nsub\npush i\nstore\npush i\nload\npush 0x05\njp neq\npush sum\nload\n",
-    ).unwrap();
-    let mem = mcpu::assembler::parse(&tokens).unwrap();
-    let mut emu = mcpu::Emulator::new();
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use mcpu::assembler::{Assembler, ParserError};
+use mcpu::debug::DebugInfo;
+use mcpu::format::Format;
+use mcpu::formatter::format_source;
+use mcpu::image::Image;
+use mcpu::include::{Loader, RealFileSystem};
+use mcpu::lint::lint;
+use mcpu::macros::Expanded;
+use mcpu::source::SourceMap;
+use mcpu::stack;
+
+fn usage() -> ! {
+    eprintln!("usage: mcpu [-I <dir>]... [-D <name>[=<value>]]... [-W] [-S] [-l <listing>] [-g <debug info>]");
+    eprintln!("            [-c <object> | -o <image> [-f <format>] [-e <entry label>]] <source>");
+    eprintln!("       mcpu <image.mcpu>");
+    eprintln!("       mcpu fmt [--check] <source>...");
+    process::exit(2);
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(1);
+}
 
-    println!("{:?}", mem);
+// Parses `NAME` or `NAME=VALUE`, with a decimal or 0x prefixed value.
+fn define(assembler: Assembler, arg: &str) -> Assembler {
+    let (name, value) = match arg.find('=') {
+        Some(index) => (&arg[..index], &arg[index + 1..]),
+        None => (arg, "1"),
+    };
+    let value = if value.starts_with("0x") || value.starts_with("0X") {
+        i64::from_str_radix(&value[2..], 16)
+    } else {
+        value.parse()
+    };
+    match value {
+        Ok(value) if !name.is_empty() => assembler.define(name, value),
+        _ => fail(format!("invalid define {}", arg)),
+    }
+}
+
+fn parser_error(err: ParserError, sources: &SourceMap, expanded: &Expanded) -> ! {
+    let mut message = format!("{}: {}", sources.describe(err.token().position()), err);
+    for expansion in expanded.backtrace(err.token()) {
+        message += &format!(
+            "\n  in macro {} used at {}, defined at {}",
+            expansion.name,
+            sources.describe(expansion.use_site),
+            sources.describe(expansion.definition)
+        );
+    }
+    fail(message)
+}
+
+fn write(path: &str, data: &[u8]) {
+    fs::write(path, data).unwrap_or_else(|err| fail(format!("cannot write {}: {}", path, err)));
+}
 
-    emu.load(&mem);
+// Runs an image on the default machine and prints its memory.
+fn run(image: &Image) {
+    let mut emu = mcpu::Emulator::new();
+    emu.load_image(image).unwrap_or_else(|err| fail(err.to_string()));
     emu.reset();
-    emu.run();
+    if let mcpu::Outcome::Fault(fault) = emu.run() {
+        let pc = emu.memory[emu.config().pc()] as usize;
+        match &image.debug {
+            Some(debug) => eprintln!("{:?} at {}", fault, debug.describe(pc)),
+            None => eprintln!("{:?} at {:02x}", fault, pc),
+        }
+    }
 
     for byte in emu.memory.iter() {
         print!("{} ", byte);
     }
 }
+
+// Prints the stack depth before each instruction, the problems found unless
+// the linter reports them already, and the most stack the program needs.
+fn print_stack(analysis: &stack::Analysis, code: &[u8], sources: &SourceMap, problems: bool) {
+    for point in &analysis.points {
+        let (text, _) = mcpu::disasm::disassemble(code, point.address);
+        eprintln!(
+            "{}: {:02X}  {:<12} {}",
+            sources.describe(point.position),
+            point.address,
+            text,
+            stack::describe(point.depth)
+        );
+    }
+    for problem in analysis.problems.iter().filter(|_| problems) {
+        eprintln!("warning: {}: {}", sources.describe(problem.position), problem.message);
+    }
+    match analysis.max {
+        Some(max) => eprintln!("max stack: {} bytes", max),
+        None => eprintln!("max stack: unbounded"),
+    }
+    if !analysis.complete {
+        eprintln!("note: jumps to computed addresses were not followed");
+    }
+}
+
+// Formats sources in place, or with `--check` lists the ones that are not
+// formatted and fails.
+fn fmt<I: Iterator<Item = String>>(args: I) {
+    let mut check = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_ref() {
+            "--check" => check = true,
+            _ if !arg.starts_with('-') => paths.push(arg),
+            _ => usage(),
+        }
+    }
+    if paths.is_empty() {
+        usage();
+    }
+
+    let mut unformatted = false;
+    for path in paths {
+        let source = fs::read_to_string(&path).unwrap_or_else(|err| fail(format!("cannot read {}: {}", path, err)));
+        let formatted = format_source(&source).unwrap_or_else(|err| {
+            let mut sources = SourceMap::new();
+            sources.add(Path::new(&path), &source);
+            fail(format!("{}: {}", sources.describe(err.position()), err))
+        });
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{}", path);
+            unformatted = true;
+        } else {
+            write(&path, formatted.as_bytes());
+        }
+    }
+    if unformatted {
+        process::exit(1);
+    }
+}
+
+fn main() {
+    if env::args().nth(1).as_deref() == Some("fmt") {
+        fmt(env::args().skip(2));
+        return;
+    }
+
+    let mut loader = Loader::new(RealFileSystem);
+    let mut assembler = Assembler::new();
+    let mut source = None;
+    let mut listing = None;
+    let mut debug = None;
+    let mut object = None;
+    let mut image = None;
+    let mut format = None;
+    let mut container = false;
+    let mut entry = None;
+    let mut warn = false;
+    let mut stack_report = false;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "-I" => match args.next() {
+                Some(dir) => loader = loader.include_path(dir),
+                None => usage(),
+            },
+            _ if arg.starts_with("-I") => loader = loader.include_path(&arg[2..]),
+            "-D" => match args.next() {
+                Some(name) => assembler = define(assembler, &name),
+                None => usage(),
+            },
+            _ if arg.starts_with("-D") => assembler = define(assembler, &arg[2..]),
+            "-l" => match args.next() {
+                Some(path) => listing = Some(path),
+                None => usage(),
+            },
+            "-g" => match args.next() {
+                Some(path) => debug = Some(path),
+                None => usage(),
+            },
+            "-c" => match args.next() {
+                Some(path) => object = Some(path),
+                None => usage(),
+            },
+            "-o" => match args.next() {
+                Some(path) => image = Some(path),
+                None => usage(),
+            },
+            "-f" => match args.next() {
+                Some(ref name) if name == "image" => container = true,
+                Some(name) => format = Some(Format::from_name(&name).unwrap_or_else(|| usage())),
+                None => usage(),
+            },
+            "-e" => match args.next() {
+                Some(label) => entry = Some(label),
+                None => usage(),
+            },
+            "-W" => warn = true,
+            "-S" => stack_report = true,
+            _ if source.is_none() && !arg.starts_with('-') => source = Some(arg),
+            _ => usage(),
+        }
+    }
+    let source = source.unwrap_or_else(|| usage());
+
+    if source.ends_with(".mcpu") {
+        let data = fs::read(&source).unwrap_or_else(|err| fail(format!("cannot read {}: {}", source, err)));
+        let image = Image::from_bytes(&data).unwrap_or_else(|err| fail(format!("{}: {}", source, err)));
+        run(&image);
+        return;
+    }
+
+    let loaded = loader
+        .load(&source)
+        .unwrap_or_else(|err| fail(err.to_string()));
+    let sources = &loaded.sources;
+
+    let expanded = mcpu::macros::expand(loaded.tokens).unwrap_or_else(|err| {
+        let mut message = format!("{}: {}", sources.describe(err.position()), err.message());
+        if let Some(definition) = err.definition() {
+            message += &format!("\n  macro defined at {}", sources.describe(definition));
+        }
+        fail(message)
+    });
+
+    if let Some(path) = object {
+        let mut object = assembler
+            .assemble_object(&expanded.tokens)
+            .unwrap_or_else(|err| parser_error(err, sources, &expanded));
+        object.name = source;
+        write(&path, &object.to_bytes());
+        return;
+    }
+
+    let parsed = mcpu::ast::parse(&expanded.tokens).unwrap_or_else(|err| parser_error(err, sources, &expanded));
+    let (mem, listed) = assembler
+        .encode_with_listing(&parsed)
+        .unwrap_or_else(|err| parser_error(err, sources, &expanded));
+
+    if let Some(path) = listing {
+        write(&path, listed.to_text(sources).as_bytes());
+    }
+
+    let debug_info = DebugInfo::new(&listed, sources);
+    if let Some(path) = debug {
+        write(&path, debug_info.to_text().as_bytes());
+    }
+
+    let mut program = Image::new(mem);
+    if let Some(label) = entry {
+        match debug_info.symbol(&label) {
+            Some(address) => program.entry_point = address as u8,
+            None => fail(format!("unknown entry label {}", label)),
+        }
+    }
+    if warn {
+        for warning in lint(&parsed, &listed, program.entry_point as usize) {
+            eprintln!("warning: {}: {}", sources.describe(warning.position), warning);
+        }
+    }
+    if stack_report {
+        print_stack(&stack::analyze(&listed, program.entry_point as usize), &program.code, sources, !warn);
+    }
+    program.symbols = Some(listed.symbols.clone());
+    program.debug = Some(debug_info);
+
+    if let Some(path) = image {
+        if container || path.ends_with(".mcpu") {
+            let bytes = program.to_bytes().unwrap_or_else(|err| fail(err.to_string()));
+            write(&path, &bytes);
+        } else {
+            let format = format
+                .or_else(|| Format::from_path(Path::new(&path)))
+                .unwrap_or(Format::Raw);
+            write(&path, &format.write(&program.code));
+        }
+        return;
+    }
+
+    println!("{:?}", program.code);
+    run(&program);
+}
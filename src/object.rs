@@ -0,0 +1,408 @@
+use std::fmt;
+
+use reader::{Reader, UnexpectedEnd};
+
+const OBJECT_MAGIC: &[u8; 4] = b"MCPO";
+const LIBRARY_MAGIC: &[u8; 4] = b"MCPA";
+const VERSION: u8 = 1;
+
+/// What a relocated byte is relative to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// The start of a section of the same object, by index.
+    Section(usize),
+    /// The address of an imported symbol.
+    Symbol(String),
+}
+
+/// A byte in a section that needs the address of `target` added once the
+/// object is placed in memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    pub offset: usize,
+    pub target: Target,
+    pub addend: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub name: String,
+    pub data: Vec<u8>,
+    /// The section is placed at a multiple of this.
+    pub align: usize,
+    pub relocations: Vec<Relocation>,
+}
+
+/// A symbol other objects may import. `section` is `None` for constants.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Export {
+    pub name: String,
+    pub section: Option<usize>,
+    pub value: i64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Object {
+    pub name: String,
+    pub sections: Vec<Section>,
+    pub exports: Vec<Export>,
+    pub imports: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjectError {
+    position: usize,
+    reason: &'static str,
+}
+
+impl fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid object at byte {}: {}", self.position, self.reason)
+    }
+}
+
+impl From<UnexpectedEnd> for ObjectError {
+    fn from(err: UnexpectedEnd) -> ObjectError {
+        ObjectError {
+            position: err.position,
+            reason: "unexpected end of data",
+        }
+    }
+}
+
+impl Object {
+    /// Encodes the object as `MCPO` and a version byte, followed by the
+    /// name, the sections with their relocations, the exports and the
+    /// imports. Counts and lengths are u16 and values i64, little endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(OBJECT_MAGIC);
+        out.push(VERSION);
+        write_str(&mut out, &self.name);
+
+        write_u16(&mut out, self.sections.len());
+        for section in &self.sections {
+            write_str(&mut out, &section.name);
+            write_u16(&mut out, section.align);
+            write_u16(&mut out, section.data.len());
+            out.extend_from_slice(&section.data);
+            write_u16(&mut out, section.relocations.len());
+            for relocation in &section.relocations {
+                write_u16(&mut out, relocation.offset);
+                match &relocation.target {
+                    Target::Section(index) => {
+                        out.push(0);
+                        write_u16(&mut out, *index);
+                    }
+                    Target::Symbol(name) => {
+                        out.push(1);
+                        write_str(&mut out, name);
+                    }
+                }
+                out.extend_from_slice(&relocation.addend.to_le_bytes());
+            }
+        }
+
+        write_u16(&mut out, self.exports.len());
+        for export in &self.exports {
+            write_str(&mut out, &export.name);
+            match export.section {
+                Some(index) => {
+                    out.push(1);
+                    write_u16(&mut out, index);
+                }
+                None => out.push(0),
+            }
+            out.extend_from_slice(&export.value.to_le_bytes());
+        }
+
+        write_u16(&mut out, self.imports.len());
+        for import in &self.imports {
+            write_str(&mut out, import);
+        }
+
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Object, ObjectError> {
+        let mut decoder = Decoder::new(data, OBJECT_MAGIC)?;
+        let object = decoder.object()?;
+        decoder.finish()?;
+        Ok(object)
+    }
+}
+
+/// A collection of objects. The linker only uses the objects that define
+/// a symbol some other object imports.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Library {
+    pub objects: Vec<Object>,
+}
+
+impl Library {
+    /// Encodes the library as `MCPA`, a version byte and the count of
+    /// objects, each prefixed with its u32 length.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(LIBRARY_MAGIC);
+        out.push(VERSION);
+        write_u16(&mut out, self.objects.len());
+        for object in &self.objects {
+            let bytes = object.to_bytes();
+            out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            out.extend_from_slice(&bytes);
+        }
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Library, ObjectError> {
+        let mut decoder = Decoder::new(data, LIBRARY_MAGIC)?;
+        let mut library = Library::default();
+        for _ in 0..decoder.u16()? {
+            let len = decoder.reader.u32()? as usize;
+            let start = decoder.reader.position();
+            let bytes = decoder.reader.take(len)?;
+            let object = Object::from_bytes(bytes).map_err(|err| ObjectError {
+                position: start + err.position,
+                reason: err.reason,
+            })?;
+            library.objects.push(object);
+        }
+        decoder.finish()?;
+        Ok(library)
+    }
+
+    /// Whether `data` looks like a library rather than an object.
+    pub fn is_library(data: &[u8]) -> bool {
+        data.starts_with(LIBRARY_MAGIC)
+    }
+}
+
+fn write_u16(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&(value as u16).to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, value: &str) {
+    write_u16(out, value.len());
+    out.extend_from_slice(value.as_bytes());
+}
+
+// Decodes objects and libraries, adding names and headers to `Reader`.
+struct Decoder<'a> {
+    reader: Reader<'a>,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8], magic: &[u8; 4]) -> Result<Decoder<'a>, ObjectError> {
+        if data.len() < 5 || &data[..4] != magic {
+            return Err(ObjectError {
+                position: 0,
+                reason: "missing header",
+            });
+        }
+        if data[4] != VERSION {
+            return Err(ObjectError {
+                position: 4,
+                reason: "unsupported version",
+            });
+        }
+        Ok(Decoder {
+            reader: Reader::new(data, 5),
+        })
+    }
+
+    fn error(&self, reason: &'static str) -> ObjectError {
+        ObjectError {
+            position: self.reader.position(),
+            reason,
+        }
+    }
+
+    fn u16(&mut self) -> Result<usize, ObjectError> {
+        Ok(self.reader.u16()? as usize)
+    }
+
+    fn string(&mut self) -> Result<String, ObjectError> {
+        let len = self.u16()?;
+        let bytes = self.reader.take(len)?;
+        match String::from_utf8(bytes.to_vec()) {
+            Ok(string) => Ok(string),
+            Err(_) => Err(self.error("invalid UTF-8 in name")),
+        }
+    }
+
+    fn finish(&self) -> Result<(), ObjectError> {
+        if !self.reader.is_at_end() {
+            return Err(self.error("trailing data"));
+        }
+        Ok(())
+    }
+
+    fn object(&mut self) -> Result<Object, ObjectError> {
+        let mut object = Object {
+            name: self.string()?,
+            ..Object::default()
+        };
+
+        for _ in 0..self.u16()? {
+            let name = self.string()?;
+            let align = self.u16()?;
+            let len = self.u16()?;
+            let data = self.reader.take(len)?.to_vec();
+            let mut relocations = Vec::new();
+            for _ in 0..self.u16()? {
+                let offset = self.u16()?;
+                let target = match self.reader.byte()? {
+                    0 => Target::Section(self.u16()?),
+                    1 => Target::Symbol(self.string()?),
+                    _ => return Err(self.error("unknown relocation target")),
+                };
+                if offset >= data.len() {
+                    return Err(self.error("relocation outside of section"));
+                }
+                relocations.push(Relocation {
+                    offset,
+                    target,
+                    addend: self.reader.i64()?,
+                });
+            }
+            object.sections.push(Section {
+                name,
+                data,
+                align,
+                relocations,
+            });
+        }
+
+        for _ in 0..self.u16()? {
+            let name = self.string()?;
+            let section = match self.reader.byte()? {
+                0 => None,
+                1 => Some(self.u16()?),
+                _ => return Err(self.error("unknown export kind")),
+            };
+            object.exports.push(Export {
+                name,
+                section,
+                value: self.reader.i64()?,
+            });
+        }
+
+        for _ in 0..self.u16()? {
+            object.imports.push(self.string()?);
+        }
+
+        let sections = object.sections.len();
+        let invalid_relocation = object.sections.iter().any(|section| {
+            section
+                .relocations
+                .iter()
+                .any(|relocation| matches!(relocation.target, Target::Section(index) if index >= sections))
+        });
+        let invalid_export = object
+            .exports
+            .iter()
+            .any(|export| export.section.is_some_and(|index| index >= sections));
+        if invalid_relocation || invalid_export {
+            return Err(self.error("unknown section index"));
+        }
+
+        Ok(object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object() -> Object {
+        Object {
+            name: "main.o".to_string(),
+            sections: vec![
+                Section {
+                    name: "text".to_string(),
+                    data: vec![0x03, 0, 0x03, 0, 0x00],
+                    align: 1,
+                    relocations: vec![
+                        Relocation {
+                            offset: 1,
+                            target: Target::Section(1),
+                            addend: 2,
+                        },
+                        Relocation {
+                            offset: 3,
+                            target: Target::Symbol("putc".to_string()),
+                            addend: -1,
+                        },
+                    ],
+                },
+                Section {
+                    name: "data".to_string(),
+                    data: vec![1, 2, 3],
+                    align: 4,
+                    relocations: Vec::new(),
+                },
+            ],
+            exports: vec![
+                Export {
+                    name: "main".to_string(),
+                    section: Some(0),
+                    value: 0,
+                },
+                Export {
+                    name: "SIZE".to_string(),
+                    section: None,
+                    value: -3,
+                },
+            ],
+            imports: vec!["putc".to_string()],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let object = object();
+        assert_eq!(Object::from_bytes(&object.to_bytes()).unwrap(), object);
+
+        let library = Library {
+            objects: vec![object, Object::default()],
+        };
+        let bytes = library.to_bytes();
+        assert!(Library::is_library(&bytes));
+        assert_eq!(Library::from_bytes(&bytes).unwrap(), library);
+    }
+
+    #[test]
+    fn rejects_malformed_objects() {
+        let reason = |data: &[u8]| Object::from_bytes(data).unwrap_err().reason;
+        let bytes = object().to_bytes();
+
+        assert_eq!(reason(b"MCPA\x01"), "missing header");
+        assert_eq!(reason(b"MCPO\x02"), "unsupported version");
+        assert_eq!(reason(&bytes[..bytes.len() - 1]), "unexpected end of data");
+        assert_eq!(reason(&[&bytes[..], &[0]].concat()), "trailing data");
+
+        let mut bad = object();
+        bad.sections[0].relocations[0].target = Target::Section(2);
+        assert_eq!(reason(&bad.to_bytes()), "unknown section index");
+
+        let mut bad = object();
+        bad.exports[0].section = Some(5);
+        assert_eq!(reason(&bad.to_bytes()), "unknown section index");
+
+        let mut bad = object();
+        bad.sections[1].relocations.push(Relocation {
+            offset: 3,
+            target: Target::Section(0),
+            addend: 0,
+        });
+        assert_eq!(reason(&bad.to_bytes()), "relocation outside of section");
+
+        let library = Library {
+            objects: vec![object()],
+        }
+        .to_bytes();
+        let err = Library::from_bytes(&library[..library.len() - 1]).unwrap_err();
+        assert_eq!(err.reason, "unexpected end of data");
+    }
+}
@@ -0,0 +1,65 @@
+/// The data ended in the middle of a field that starts at `position`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnexpectedEnd {
+    pub position: usize,
+}
+
+/// Reads little endian fields from the binary formats: objects, images,
+/// snapshots and traces.
+pub struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Starts reading `data` at `position`, e.g. after a header that was
+    /// already checked.
+    pub fn new(data: &'a [u8], position: usize) -> Reader<'a> {
+        Reader { data, position }
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], UnexpectedEnd> {
+        if len > self.data.len().saturating_sub(self.position) {
+            return Err(UnexpectedEnd {
+                position: self.position,
+            });
+        }
+        let bytes = &self.data[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    pub fn byte(&mut self) -> Result<u8, UnexpectedEnd> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, UnexpectedEnd> {
+        let mut bytes = [0u8; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, UnexpectedEnd> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, UnexpectedEnd> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn i64(&mut self) -> Result<i64, UnexpectedEnd> {
+        Ok(self.u64()? as i64)
+    }
+}
@@ -0,0 +1,223 @@
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+
+use reader::{Reader, UnexpectedEnd};
+use Fault;
+
+const MAGIC: &[u8; 4] = b"MCPS";
+const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub memory: Vec<u8>,
+    pub running: bool,
+    pub fault: Option<Fault>,
+    pub cycles: u64,
+    pub steps: u64,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    Invalid(&'static str),
+    UnsupportedVersion(u8),
+    SizeMismatch { expected: usize, found: usize },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "{}", err),
+            SnapshotError::Invalid(reason) => write!(f, "invalid snapshot: {}", reason),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::SizeMismatch { expected, found } => write!(
+                f,
+                "snapshot has {} bytes of memory, machine has {}",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> SnapshotError {
+        SnapshotError::Io(err)
+    }
+}
+
+impl From<UnexpectedEnd> for SnapshotError {
+    fn from(_: UnexpectedEnd) -> SnapshotError {
+        SnapshotError::Invalid("unexpected end of data")
+    }
+}
+
+impl Snapshot {
+    /// Encodes the snapshot as `MCPS`, a version byte, the flags, the fault,
+    /// both counters and the length-prefixed memory.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.memory.len() + 32);
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.push(self.running as u8);
+        match self.fault {
+            None => out.push(0),
+            Some(Fault::IllegalInstruction { address, opcode }) => {
+                out.push(1);
+                out.push(address);
+                out.push(opcode);
+            }
+            Some(Fault::StackOverflow) => out.push(2),
+        }
+        out.extend_from_slice(&self.cycles.to_le_bytes());
+        out.extend_from_slice(&self.steps.to_le_bytes());
+        out.extend_from_slice(&(self.memory.len() as u16).to_le_bytes());
+        out.extend_from_slice(&self.memory);
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Snapshot, SnapshotError> {
+        if data.len() < 5 || &data[..4] != MAGIC {
+            return Err(SnapshotError::Invalid("missing MCPS header"));
+        }
+        if data[4] != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(data[4]));
+        }
+
+        let mut reader = Reader::new(data, 5);
+        let running = reader.byte()? != 0;
+        let fault = match reader.byte()? {
+            0 => None,
+            1 => Some(Fault::IllegalInstruction {
+                address: reader.byte()?,
+                opcode: reader.byte()?,
+            }),
+            2 => Some(Fault::StackOverflow),
+            _ => return Err(SnapshotError::Invalid("unknown fault")),
+        };
+        let cycles = reader.u64()?;
+        let steps = reader.u64()?;
+        let len = reader.u16()? as usize;
+        let memory = reader.take(len)?.to_vec();
+
+        if !reader.is_at_end() {
+            return Err(SnapshotError::Invalid("trailing data"));
+        }
+
+        Ok(Snapshot {
+            memory,
+            running,
+            fault,
+            cycles,
+            steps,
+        })
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_bytes())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Snapshot, SnapshotError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Snapshot::from_bytes(&data)
+    }
+
+    pub fn to_json(&self) -> String {
+        let fault = match self.fault {
+            None => "null".to_string(),
+            Some(Fault::IllegalInstruction { address, opcode }) => format!(
+                "{{\"kind\":\"illegal_instruction\",\"address\":{},\"opcode\":{}}}",
+                address, opcode
+            ),
+            Some(Fault::StackOverflow) => "{\"kind\":\"stack_overflow\"}".to_string(),
+        };
+
+        format!(
+            "{{\"version\":{},\"running\":{},\"fault\":{},\"cycles\":{},\"steps\":{},\"memory\":{:?}}}",
+            VERSION, self.running, fault, self.cycles, self.steps, self.memory
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Emulator;
+
+    fn snapshot(fault: Option<Fault>) -> Snapshot {
+        Snapshot {
+            memory: (0..=255).collect(),
+            running: fault.is_none(),
+            fault,
+            cycles: 1 << 40,
+            steps: 12,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let faults = [
+            None,
+            Some(Fault::StackOverflow),
+            Some(Fault::IllegalInstruction { address: 3, opcode: 0xEE }),
+        ];
+        for fault in &faults {
+            let snapshot = snapshot(*fault);
+            assert_eq!(Snapshot::from_bytes(&snapshot.to_bytes()).unwrap(), snapshot);
+
+            let mut file = Vec::new();
+            snapshot.write_to(&mut file).unwrap();
+            assert_eq!(Snapshot::read_from(&mut &file[..]).unwrap(), snapshot);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_data() {
+        let data = snapshot(None).to_bytes();
+        let err = |data: &[u8]| Snapshot::from_bytes(data).unwrap_err();
+
+        assert!(matches!(err(b"MCPX\x01"), SnapshotError::Invalid("missing MCPS header")));
+        assert!(matches!(err(b"MCPS\x07"), SnapshotError::UnsupportedVersion(7)));
+        assert!(matches!(err(&data[..data.len() - 1]), SnapshotError::Invalid("unexpected end of data")));
+
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert!(matches!(err(&trailing), SnapshotError::Invalid("trailing data")));
+
+        let mut fault = data;
+        fault[6] = 9;
+        assert!(matches!(err(&fault), SnapshotError::Invalid("unknown fault")));
+    }
+
+    #[test]
+    fn restore() {
+        let mut emu = Emulator::new();
+        emu.load(&[0x03, 0x05, 0x00]).unwrap();
+        emu.reset();
+        let start = emu.snapshot();
+        emu.run();
+        assert!(!emu.running);
+
+        emu.restore(&start).unwrap();
+        assert_eq!(emu.snapshot(), start);
+
+        let small = Snapshot {
+            memory: vec![0; 16],
+            ..start
+        };
+        assert!(matches!(
+            emu.restore(&small),
+            Err(SnapshotError::SizeMismatch { expected: 256, found: 16 })
+        ));
+    }
+
+    #[test]
+    fn json() {
+        let json = snapshot(Some(Fault::StackOverflow)).to_json();
+        assert!(json.starts_with("{\"version\":1,\"running\":false,\"fault\":{\"kind\":\"stack_overflow\"},"));
+        assert!(json.contains("\"cycles\":1099511627776,\"steps\":12,\"memory\":[0, 1, 2,"));
+    }
+}
@@ -0,0 +1,80 @@
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    pub source: String,
+    pub start: usize,
+}
+
+impl SourceFile {
+    /// 1-based line and column of a byte offset local to this file.
+    /// Columns count characters.
+    pub fn line_col(&self, position: usize) -> (usize, usize) {
+        let before = self.source.get(..position).unwrap_or(&self.source);
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        let line = before.matches('\n').count() + 1;
+        (line, before[line_start..].chars().count() + 1)
+    }
+
+    /// Text of the line a local position is on, without the line break.
+    pub fn line_text(&self, position: usize) -> &str {
+        let (line, _) = self.line_col(position);
+        self.source.lines().nth(line - 1).unwrap_or("")
+    }
+
+    pub fn len(&self) -> usize {
+        self.source.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.source.is_empty()
+    }
+}
+
+/// Token positions of all loaded files share one range of byte offsets;
+/// each file occupies the positions from its `start` up to its length.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    pub files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap { files: Vec::new() }
+    }
+
+    /// Adds a file and returns the offset its token positions start at.
+    pub fn add(&mut self, path: &Path, source: &str) -> usize {
+        let start = match self.files.last() {
+            Some(file) => file.start + file.len() + 1,
+            None => 0,
+        };
+        self.files.push(SourceFile {
+            path: path.to_path_buf(),
+            source: source.to_string(),
+            start,
+        });
+        start
+    }
+
+    /// The file a position belongs to and the position within it.
+    pub fn lookup(&self, position: usize) -> Option<(&SourceFile, usize)> {
+        self.files
+            .iter()
+            .rev()
+            .find(|file| file.start <= position)
+            .map(|file| (file, position - file.start))
+    }
+
+    /// Formats a position as `path:line:column`.
+    pub fn describe(&self, position: usize) -> String {
+        match self.lookup(position) {
+            Some((file, local)) => {
+                let (line, column) = file.line_col(local);
+                format!("{}:{}:{}", file.path.display(), line, column)
+            }
+            None => format!("position {}", position),
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! Computes the stack depth at every instruction of a program by following
+//! each path from the entry point with the fixed stack effect of each
+//! opcode. Depths are kept as a range, so a point that paths reach with
+//! different depths shows all of them.
+//!
+//! Jumps whose target is computed at run time are not followed; `complete`
+//! tells whether the program has any. Like the linter, such programs are
+//! also followed from every instruction whose address is pushed, starting
+//! with an empty stack wherever no path from the entry point got to first.
+
+use std::cmp;
+
+use builder::MAX_MEMORY_SIZE;
+use disasm;
+use lint::Flow;
+use listing::Listing;
+
+/// The smallest and largest number of bytes on the stack at a point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Depth {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl Depth {
+    fn join(self, other: Depth) -> Depth {
+        Depth {
+            min: cmp::min(self.min, other.min),
+            max: cmp::max(self.max, other.max),
+        }
+    }
+
+    // The depth after an instruction that pops `pops` bytes and pushes
+    // `pushes`. A depth of the size of memory stands for any depth, so loops
+    // that keep pushing still settle.
+    fn apply(self, pops: usize, pushes: usize) -> Depth {
+        let apply = |depth: usize| {
+            if depth >= MAX_MEMORY_SIZE {
+                depth
+            } else {
+                cmp::min(depth.saturating_sub(pops) + pushes, MAX_MEMORY_SIZE)
+            }
+        };
+        Depth {
+            min: apply(self.min),
+            max: apply(self.max),
+        }
+    }
+}
+
+/// An instruction execution reaches and the stack depth before it runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Point {
+    pub address: usize,
+    pub position: usize,
+    pub depth: Depth,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProblemKind {
+    /// An instruction can pop more bytes than the stack holds.
+    Underflow,
+    /// Paths with different stack depths meet at an instruction.
+    Mismatch,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub kind: ProblemKind,
+    pub address: usize,
+    pub position: usize,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Analysis {
+    /// Reached instructions in address order.
+    pub points: Vec<Point>,
+    pub problems: Vec<Problem>,
+    /// The most bytes the stack holds at any point, or `None` if it grows
+    /// without bound.
+    pub max: Option<usize>,
+    /// The point where the stack is deepest.
+    pub deepest: Option<Point>,
+    /// Whether every jump target was known, so every path was followed.
+    pub complete: bool,
+}
+
+/// Analyses the code in `listing`, starting with an empty stack at `entry`.
+pub fn analyze(listing: &Listing, entry: usize) -> Analysis {
+    analyze_flow(&Flow::new(listing, entry))
+}
+
+/// Analyses `flow` from its roots, the entry point first.
+pub(crate) fn analyze_flow(flow: &Flow) -> Analysis {
+    let effects: Vec<(usize, usize)> = flow
+        .instructions
+        .iter()
+        .map(|instruction| disasm::stack_effect(instruction.opcode, instruction.operand).unwrap_or((0, 0)))
+        .collect();
+
+    let empty = Depth { min: 0, max: 0 };
+    let mut depths: Vec<Option<Depth>> = vec![None; flow.instructions.len()];
+    let mut seeds = Vec::new();
+    for root in &flow.roots {
+        if depths[*root].is_some() {
+            continue;
+        }
+        depths[*root] = Some(empty);
+        seeds.push(*root);
+
+        let mut pending = vec![*root];
+        while let Some(index) = pending.pop() {
+            let (pops, pushes) = effects[index];
+            let after = depths[index].map(|depth| depth.apply(pops, pushes));
+            for next in &flow.successors[index] {
+                let joined = match (depths[*next], after) {
+                    (Some(known), Some(after)) => Some(known.join(after)),
+                    (known, after) => known.or(after),
+                };
+                if joined != depths[*next] {
+                    depths[*next] = joined;
+                    pending.push(*next);
+                }
+            }
+        }
+    }
+
+    let mut analysis = Analysis {
+        points: Vec::new(),
+        problems: Vec::new(),
+        max: Some(0),
+        deepest: None,
+        complete: !flow.dynamic,
+    };
+    // the depths each path arrives with, an empty stack where it starts
+    let mut incoming: Vec<Vec<Depth>> = vec![Vec::new(); flow.instructions.len()];
+    for seed in seeds {
+        incoming[seed].push(empty);
+    }
+    for (index, instruction) in flow.instructions.iter().enumerate() {
+        let depth = match depths[index] {
+            Some(depth) => depth,
+            None => continue,
+        };
+        let point = Point {
+            address: instruction.address,
+            position: instruction.position,
+            depth,
+        };
+        analysis.points.push(point);
+
+        let (pops, pushes) = effects[index];
+        if depth.min < pops {
+            analysis.problems.push(Problem {
+                kind: ProblemKind::Underflow,
+                address: instruction.address,
+                position: instruction.position,
+                message: format!(
+                    "{} pops {} but the stack may hold only {}",
+                    disasm::format_instruction(instruction.opcode, instruction.operand),
+                    pops,
+                    depth.min
+                ),
+            });
+        }
+
+        let after = depth.apply(pops, pushes);
+        let peak = cmp::max(depth.max, after.max);
+        if analysis.deepest.is_none_or(|deepest| peak > deepest.depth.max) {
+            analysis.deepest = Some(Point {
+                depth: Depth { min: depth.min, max: peak },
+                ..point
+            });
+        }
+        for next in &flow.successors[index] {
+            incoming[*next].push(after);
+        }
+    }
+
+    for (index, depths) in incoming.iter().enumerate() {
+        if let Some(other) = depths.iter().find(|depth| **depth != depths[0]) {
+            let instruction = flow.instructions[index];
+            analysis.problems.push(Problem {
+                kind: ProblemKind::Mismatch,
+                address: instruction.address,
+                position: instruction.position,
+                message: format!(
+                    "paths reach {:02X} with stack depths {} and {}",
+                    instruction.address,
+                    describe(depths[0]),
+                    describe(*other)
+                ),
+            });
+        }
+    }
+    analysis.problems.sort_by_key(|problem| problem.address);
+
+    analysis.max = match analysis.deepest {
+        Some(deepest) if deepest.depth.max >= MAX_MEMORY_SIZE => None,
+        Some(deepest) => Some(deepest.depth.max),
+        None => Some(0),
+    };
+    analysis
+}
+
+/// Formats a depth as `n` or `min..max`, with `?` for an unbounded end.
+pub fn describe(depth: Depth) -> String {
+    let bound = |value: usize| {
+        if value >= MAX_MEMORY_SIZE {
+            "?".to_string()
+        } else {
+            value.to_string()
+        }
+    };
+    if depth.min == depth.max {
+        bound(depth.min)
+    } else {
+        format!("{}..{}", bound(depth.min), bound(depth.max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::{tokenize, Assembler};
+
+    fn analyze_source(source: &str) -> Analysis {
+        let tokens = tokenize(source).unwrap();
+        let (_, listing) = Assembler::new().assemble_with_listing(&tokens).unwrap();
+        analyze(&listing, 0)
+    }
+
+    fn depths(analysis: &Analysis) -> Vec<(usize, String)> {
+        analysis
+            .points
+            .iter()
+            .map(|point| (point.address, describe(point.depth)))
+            .collect()
+    }
+
+    fn messages(analysis: &Analysis) -> Vec<&str> {
+        analysis.problems.iter().map(|problem| problem.message.as_str()).collect()
+    }
+
+    #[test]
+    fn straight_line() {
+        let analysis = analyze_source("push 1\npush 2\nadd\nhalt\n");
+        let expected = vec![(0, "0"), (2, "1"), (4, "2"), (5, "1")];
+        let expected: Vec<(usize, String)> = expected.into_iter().map(|(a, d)| (a, d.to_string())).collect();
+        assert_eq!(depths(&analysis), expected);
+        assert_eq!(analysis.max, Some(2));
+        assert_eq!(analysis.deepest.unwrap().address, 2);
+        assert!(analysis.problems.is_empty() && analysis.complete);
+    }
+
+    #[test]
+    fn problems() {
+        let analysis = analyze_source("pop\nhalt\n");
+        assert_eq!(messages(&analysis), vec!["pop pops 1 but the stack may hold only 0"]);
+        assert_eq!(analysis.problems[0].kind, ProblemKind::Underflow);
+
+        let analysis = analyze_source("push 0\npush skip - 1\njp eq\npush 1\nskip: halt\n");
+        assert_eq!(messages(&analysis), vec!["paths reach 08 with stack depths 0 and 1"]);
+        assert_eq!(analysis.problems[0].kind, ProblemKind::Mismatch);
+
+        let analysis = analyze_source("loop: push 1\npush loop - 1\njp\n");
+        assert_eq!(analysis.max, None);
+        assert_eq!(describe(analysis.points[0].depth), "0..?");
+    }
+
+    #[test]
+    fn follows_pushed_addresses_after_computed_jumps() {
+        let source = "push func - 1\npush 0x40\nstore\npush 0x40\nload\njp\nhalt\nfunc: push 1\npop\npop\nhalt\n";
+        let analysis = analyze_source(source);
+        assert!(!analysis.complete);
+        assert!(analysis.points.iter().any(|point| point.address == 11));
+        assert_eq!(messages(&analysis), vec!["pop pops 1 but the stack may hold only 0"]);
+        assert_eq!(analysis.problems[0].address, 14);
+    }
+}
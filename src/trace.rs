@@ -0,0 +1,288 @@
+use std::fmt;
+
+use debug::DebugInfo;
+use disasm;
+use reader::{Reader, UnexpectedEnd};
+
+const MAGIC: &[u8; 4] = b"MCPT";
+const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub address: u8,
+    pub old: u8,
+    pub new: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceStep {
+    pub cycle: u64,
+    pub pc: u8,
+    pub opcode: u8,
+    pub operand: Option<u8>,
+    pub stack_before: Vec<u8>,
+    pub stack_after: Vec<u8>,
+    pub writes: Vec<MemoryWrite>,
+}
+
+impl TraceStep {
+    pub fn mnemonic(&self) -> String {
+        disasm::format_instruction(self.opcode, self.operand)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceError {
+    position: usize,
+    reason: &'static str,
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid trace at byte {}: {}", self.position, self.reason)
+    }
+}
+
+impl From<UnexpectedEnd> for TraceError {
+    fn from(err: UnexpectedEnd) -> TraceError {
+        TraceError {
+            position: err.position,
+            reason: "unexpected end of data",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub steps: Vec<TraceStep>,
+}
+
+impl Trace {
+    pub fn new() -> Trace {
+        Trace { steps: Vec::new() }
+    }
+
+    pub fn record(&mut self, step: TraceStep) {
+        self.steps.push(step);
+    }
+
+    pub fn to_text(&self) -> String {
+        self.to_text_with(None)
+    }
+
+    /// Like `to_text`, but ends every step with the source location and
+    /// label of its instruction.
+    pub fn to_annotated_text(&self, debug: &DebugInfo) -> String {
+        self.to_text_with(Some(debug))
+    }
+
+    fn to_text_with(&self, debug: Option<&DebugInfo>) -> String {
+        let mut out = String::new();
+
+        for step in &self.steps {
+            out.push_str(&format!(
+                "{:>8} {:02x}: {:<12} {} -> {}",
+                step.cycle,
+                step.pc,
+                step.mnemonic(),
+                hex_list(&step.stack_before),
+                hex_list(&step.stack_after)
+            ));
+            for write in &step.writes {
+                out.push_str(&format!(
+                    " {:02x}:{:02x}->{:02x}",
+                    write.address, write.old, write.new
+                ));
+            }
+            if let Some(debug) = debug {
+                out.push_str(&format!("  ; {}", debug.describe(step.pc as usize)));
+            }
+            out.push('\n');
+        }
+
+        out
+    }
+
+    pub fn to_jsonl(&self) -> String {
+        let mut out = String::new();
+
+        for step in &self.steps {
+            let writes: Vec<String> = step
+                .writes
+                .iter()
+                .map(|write| {
+                    format!(
+                        "{{\"address\":{},\"old\":{},\"new\":{}}}",
+                        write.address, write.old, write.new
+                    )
+                })
+                .collect();
+
+            out.push_str(&format!(
+                "{{\"cycle\":{},\"pc\":{},\"opcode\":{},\"mnemonic\":\"{}\",\"stack_before\":{:?},\"stack_after\":{:?},\"writes\":[{}]}}\n",
+                step.cycle,
+                step.pc,
+                step.opcode,
+                step.mnemonic(),
+                step.stack_before,
+                step.stack_after,
+                writes.join(",")
+            ));
+        }
+
+        out
+    }
+
+    /// Encodes the trace as `MCPT`, a version byte and one record per step.
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+
+        for step in &self.steps {
+            out.extend_from_slice(&step.cycle.to_le_bytes());
+            out.push(step.pc);
+            out.push(step.opcode);
+            match step.operand {
+                Some(operand) => {
+                    out.push(1);
+                    out.push(operand);
+                }
+                None => out.push(0),
+            }
+            out.push(step.stack_before.len() as u8);
+            out.extend_from_slice(&step.stack_before);
+            out.push(step.stack_after.len() as u8);
+            out.extend_from_slice(&step.stack_after);
+            out.push(step.writes.len() as u8);
+            for write in &step.writes {
+                out.push(write.address);
+                out.push(write.old);
+                out.push(write.new);
+            }
+        }
+
+        out
+    }
+
+    pub fn from_binary(data: &[u8]) -> Result<Trace, TraceError> {
+        if data.len() < 5 || &data[..4] != MAGIC {
+            return Err(TraceError {
+                position: 0,
+                reason: "missing MCPT header",
+            });
+        }
+        if data[4] != VERSION {
+            return Err(TraceError {
+                position: 4,
+                reason: "unsupported version",
+            });
+        }
+
+        let mut reader = Reader::new(data, 5);
+        let mut trace = Trace::new();
+
+        while !reader.is_at_end() {
+            let cycle = reader.u64()?;
+            let pc = reader.byte()?;
+            let opcode = reader.byte()?;
+            let operand = match reader.byte()? {
+                0 => None,
+                _ => Some(reader.byte()?),
+            };
+            let len = reader.byte()? as usize;
+            let stack_before = reader.take(len)?.to_vec();
+            let len = reader.byte()? as usize;
+            let stack_after = reader.take(len)?.to_vec();
+            let count = reader.byte()?;
+            let mut writes = Vec::new();
+            for _ in 0..count {
+                let write = reader.take(3)?;
+                writes.push(MemoryWrite {
+                    address: write[0],
+                    old: write[1],
+                    new: write[2],
+                });
+            }
+
+            trace.record(TraceStep {
+                cycle,
+                pc,
+                opcode,
+                operand,
+                stack_before,
+                stack_after,
+                writes,
+            });
+        }
+
+        Ok(trace)
+    }
+}
+
+fn hex_list(bytes: &[u8]) -> String {
+    let items: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("[{}]", items.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Emulator;
+
+    // Traces push 5; push 0x40; store; halt.
+    fn traced() -> Trace {
+        let mut emu = Emulator::new();
+        emu.load(&[0x03, 0x05, 0x03, 0x40, 0x02, 0x00]).unwrap();
+        emu.reset();
+        emu.trace = Some(Trace::new());
+        emu.run();
+        emu.trace.unwrap()
+    }
+
+    #[test]
+    fn records_steps() {
+        let trace = traced();
+        assert_eq!(trace.steps.len(), 4);
+        let store = &trace.steps[2];
+        assert_eq!((store.pc, store.opcode, store.operand), (4, 0x02, None));
+        assert_eq!(store.stack_before, vec![0x40, 0x05]);
+        assert!(store.stack_after.is_empty());
+        assert!(store.writes.contains(&MemoryWrite {
+            address: 0x40,
+            old: 0,
+            new: 5,
+        }));
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let trace = traced();
+        assert_eq!(Trace::from_binary(&trace.to_binary()).unwrap(), trace);
+        assert_eq!(Trace::from_binary(&Trace::new().to_binary()).unwrap(), Trace::new());
+    }
+
+    #[test]
+    fn rejects_malformed_binary() {
+        assert_eq!(Trace::from_binary(b"MCPX\x01").unwrap_err().position, 0);
+        assert_eq!(Trace::from_binary(b"MCPT\x09").unwrap_err().position, 4);
+
+        let data = traced().to_binary();
+        let err = Trace::from_binary(&data[..data.len() - 1]).unwrap_err();
+        assert_eq!(err.reason, "unexpected end of data");
+    }
+
+    #[test]
+    fn text_and_jsonl() {
+        let trace = traced();
+        let text = trace.to_text();
+        assert_eq!(text.lines().count(), 4);
+        assert!(text.lines().nth(2).unwrap().contains("store"));
+        assert!(text.contains(" 40:00->05"));
+
+        let jsonl = trace.to_jsonl();
+        let first = jsonl.lines().next().unwrap();
+        assert!(first.starts_with("{\"cycle\":0,\"pc\":0,\"opcode\":3,"));
+        assert!(first.contains("\"stack_after\":[5]"));
+    }
+}